@@ -0,0 +1,45 @@
+use std::sync::Arc;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use hyper::{Body, Request};
+use redirector::App;
+
+fn request(host: &str) -> Request<Body> {
+    Request::builder()
+        .uri("/some/path?query=1")
+        .header("host", host)
+        .body(Body::empty())
+        .expect("request is valid")
+}
+
+fn app(args: &[&str]) -> Arc<App> {
+    let pairs = [
+        "old.example.com=new.example.com",
+        "*.legacy.example.com=example.com",
+        "docs.example.com/v1=docs.example.com/v2",
+    ];
+    App::from_args(["redirector", "--quiet"].iter().chain(&pairs).chain(args))
+        .expect("configuration is valid")
+}
+
+fn handle(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().expect("runtime starts");
+    let without_fallback = app(&[]);
+    let with_fallback = app(&["--fallback", "fallback.example.com"]);
+    let cases = [
+        ("matched", &without_fallback, "old.example.com"),
+        ("fallback", &with_fallback, "unknown.example.com"),
+        ("miss", &without_fallback, "unknown.example.com"),
+    ];
+    let mut group = c.benchmark_group("handle");
+    for (name, app, host) in cases {
+        group.bench_function(name, |b| {
+            b.to_async(&runtime)
+                .iter(|| app.clone().handle(request(host), None))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, handle);
+criterion_main!(benches);
@@ -0,0 +1,23 @@
+use std::process::Command;
+
+fn main() {
+    // Embed the git commit for --version. A GIT_COMMIT environment variable wins,
+    // for builds outside of a git checkout, and otherwise we fall back to "unknown"
+    let commit = std::env::var("GIT_COMMIT")
+        .ok()
+        .or_else(|| {
+            let output = Command::new("git")
+                .args(["rev-parse", "--short", "HEAD"])
+                .output()
+                .ok()
+                .filter(|output| output.status.success())?;
+            String::from_utf8(output.stdout).ok()
+        })
+        .map(|commit| commit.trim().to_owned())
+        .filter(|commit| !commit.is_empty())
+        .unwrap_or_else(|| "unknown".to_owned());
+    println!("cargo:rustc-env=GIT_COMMIT={}", commit);
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+    println!("cargo:rerun-if-env-changed=GIT_COMMIT");
+}
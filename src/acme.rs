@@ -0,0 +1,181 @@
+//! Getting and renewing certificates from Let's Encrypt
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+use anyhow::Context;
+use arc_swap::ArcSwapOption;
+use tokio_rustls::{
+    rustls::{
+        self,
+        server::{ClientHello, ResolvesServerCert},
+        sign::CertifiedKey,
+    },
+    TlsAcceptor,
+};
+
+use crate::{
+    app::App,
+    tls::{alpn_protocols, read_private_key},
+};
+
+pub(crate) const ACME_CHALLENGE_PREFIX: &str = "/.well-known/acme-challenge/";
+
+/// How often to check whether the ACME certificate needs renewing
+pub(crate) const ACME_CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// Renew the ACME certificate once it has fewer than this many days left
+pub(crate) const ACME_RENEW_DAYS: i64 = 30;
+
+/// A certificate from Let's Encrypt, along with the http-01 challenges we're
+/// answering while getting it
+pub(crate) struct Acme {
+    pub(crate) domains: Vec<String>,
+    pub(crate) email: String,
+    /// Where the account key and certificates are saved between runs
+    pub(crate) dir: PathBuf,
+    /// Host/port to serve HTTPS on
+    pub(crate) bind: SocketAddr,
+    /// Whether to use the Let's Encrypt staging environment
+    pub(crate) staging: bool,
+    /// Key authorization for each pending challenge token
+    pub(crate) challenges: Mutex<HashMap<String, String>>,
+    pub(crate) resolver: Arc<AcmeResolver>,
+    pub(crate) acceptor: TlsAcceptor,
+}
+
+/// Hands out the current certificate, swapped out whenever it's renewed.
+/// Handshakes fail until the first one has been issued.
+pub(crate) struct AcmeResolver(pub(crate) ArcSwapOption<CertifiedKey>);
+
+impl ResolvesServerCert for AcmeResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        self.0.load_full()
+    }
+}
+
+impl Acme {
+    pub(crate) fn new(
+        domains: Vec<String>,
+        email: String,
+        dir: PathBuf,
+        bind: SocketAddr,
+        staging: bool,
+        http2: bool,
+    ) -> Self {
+        let resolver = Arc::new(AcmeResolver(ArcSwapOption::empty()));
+        let mut config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(resolver.clone());
+        config.alpn_protocols = alpn_protocols(http2);
+        Acme {
+            domains,
+            email,
+            dir,
+            bind,
+            staging,
+            challenges: Mutex::new(HashMap::new()),
+            resolver,
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+        }
+    }
+
+    pub(crate) fn proof(&self, token: &str) -> Option<String> {
+        self.challenges
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(token)
+            .cloned()
+    }
+
+    /// Load the saved certificate, ordering a new one if there isn't one yet or
+    /// it expires soon. This blocks while talking to the ACME server.
+    pub(crate) fn provision(&self) -> anyhow::Result<()> {
+        let persist = acme_lib::persist::FilePersist::new(&self.dir);
+        let url = if self.staging {
+            acme_lib::DirectoryUrl::LetsEncryptStaging
+        } else {
+            acme_lib::DirectoryUrl::LetsEncrypt
+        };
+        let directory = acme_lib::Directory::from_url(persist, url)
+            .context("Unable to reach the ACME directory")?;
+        let account = directory
+            .account(&self.email)
+            .context("Unable to load the ACME account")?;
+        let primary = &self.domains[0];
+        let cert = match account.certificate(primary)? {
+            Some(cert) if cert.valid_days_left() > ACME_RENEW_DAYS => cert,
+            _ => {
+                eprintln!("Ordering a certificate for {}", self.domains.join(", "));
+                let alt_names: Vec<&str> = self.domains[1..].iter().map(String::as_str).collect();
+                let mut order = account.new_order(primary, &alt_names)?;
+                let csr = loop {
+                    if let Some(csr) = order.confirm_validations() {
+                        break csr;
+                    }
+                    for auth in order.authorizations()? {
+                        let challenge = auth.http_challenge();
+                        self.challenges
+                            .lock()
+                            .unwrap_or_else(PoisonError::into_inner)
+                            .insert(challenge.http_token().to_owned(), challenge.http_proof());
+                        challenge.validate(5000)?;
+                    }
+                    order.refresh()?;
+                };
+                self.challenges
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .clear();
+                let cert = csr
+                    .finalize_pkey(acme_lib::create_p384_key(), 5000)?
+                    .download_and_save_cert()?;
+                eprintln!(
+                    "Received a certificate valid for {} days",
+                    cert.valid_days_left()
+                );
+                cert
+            }
+        };
+        let key = certified_key(cert.certificate(), cert.private_key())?;
+        self.resolver.0.store(Some(Arc::new(key)));
+        Ok(())
+    }
+}
+
+pub(crate) fn certified_key(cert: &str, key: &str) -> anyhow::Result<CertifiedKey> {
+    let certs = rustls_pemfile::certs(&mut cert.as_bytes())?;
+    let key = read_private_key(&mut key.as_bytes())?.context("No private key found")?;
+    let key = rustls::sign::any_supported_type(&rustls::PrivateKey(key))
+        .map_err(|_| anyhow::anyhow!("Unsupported private key type"))?;
+    Ok(CertifiedKey::new(
+        certs.into_iter().map(rustls::Certificate).collect(),
+        key,
+    ))
+}
+
+/// Provision the ACME certificate now and then check for renewal periodically
+pub(crate) fn spawn_acme(app: Arc<App>) {
+    tokio::spawn(async move {
+        loop {
+            let app = app.clone();
+            let res = tokio::task::spawn_blocking(move || match &app.acme {
+                Some(acme) => acme.provision(),
+                None => Ok(()),
+            })
+            .await;
+            match res {
+                Ok(Ok(())) => (),
+                Ok(Err(e)) => eprintln!("Unable to provision ACME certificate: {:?}", e),
+                Err(e) => eprintln!("ACME task failed: {:?}", e),
+            }
+            tokio::time::sleep(ACME_CHECK_INTERVAL).await;
+        }
+    });
+}
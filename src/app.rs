@@ -0,0 +1,1076 @@
+//! Handling requests: routing them to a destination and building the redirect
+
+use std::{
+    borrow::Cow,
+    convert::Infallible,
+    fmt::Write,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::Context;
+use arc_swap::ArcSwap;
+use clap::StructOpt;
+use hyper::{
+    body::{Bytes, HttpBody},
+    header::{
+        HeaderName, HeaderValue, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+        ACCESS_CONTROL_ALLOW_ORIGIN, ALLOW, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_TYPE, HOST,
+        LINK, LOCATION, REFERER, RETRY_AFTER, SERVER, STRICT_TRANSPORT_SECURITY, USER_AGENT, VARY,
+    },
+    Body, Method, Request, Response, StatusCode, Uri,
+};
+use tokio::sync::Semaphore;
+use tokio_rustls::TlsAcceptor;
+
+#[cfg(feature = "acme")]
+use crate::acme::{Acme, ACME_CHALLENGE_PREFIX};
+
+use crate::{
+    config::{
+        AllowedMethods, Cors, CustomHeader, Maintenance, Opt, RedirectStatus, TrailingSlash,
+        WwwMode,
+    },
+    host::{add_port, is_default_port, normalize_host, port, strip_port, validate_host},
+    log::{LogFormat, LogSink, RequestLog, NORMAL, QUIET, VERBOSE},
+    metrics::{escape_label, Metrics},
+    pairs::{load_rules, Destination, DomainFallback, DomainPair, Rewrite},
+    rate_limit::RateLimiter,
+    response::{
+        accepts_gzip, collapse_slashes, escape_html, fragment_start, gzip, interstitial_page,
+        make_response, truncate, without_body, COMPRESS_THRESHOLD,
+    },
+    rules::Rules,
+    server::InboundTls,
+    tls::load_tls,
+};
+
+/// The redirect server's configuration and state, shared by every connection
+pub struct App {
+    pub(crate) rules: ArcSwap<Rules>,
+    pub(crate) rewrites: Vec<Rewrite>,
+    pub(crate) insecure: bool,
+    /// Normalized request hosts to redirect to HTTP in place of HTTPS
+    pub(crate) insecure_hosts: Vec<Vec<u8>>,
+    pub(crate) status: StatusCode,
+    /// Fallbacks for subdomains of particular domains, checked in order before
+    /// the catch-all and global fallback
+    pub(crate) domain_fallbacks: Vec<DomainFallback>,
+    /// Redirect status for requests sent to the fallback, overriding --status
+    pub(crate) fallback_status: Option<StatusCode>,
+    pub(crate) unknown_host_status: StatusCode,
+    pub(crate) error_body: Option<String>,
+    pub(crate) upgrade_only: bool,
+    pub(crate) drop_path: bool,
+    pub(crate) strict_paths: bool,
+    pub(crate) strip_query: bool,
+    pub(crate) strip_fragment: bool,
+    pub(crate) collapse_slashes: bool,
+    pub(crate) trailing_slash: TrailingSlash,
+    pub(crate) preserve_port: bool,
+    pub(crate) www: Option<WwwMode>,
+    pub(crate) request_timeout: Option<Duration>,
+    pub(crate) max_uri_length: usize,
+    pub(crate) http2: bool,
+    pub(crate) keep_alive: Option<Duration>,
+    /// Maximum number of pending connections for TCP listeners
+    pub(crate) backlog: i32,
+    /// Whether to set SO_REUSEPORT on TCP listeners
+    pub(crate) reuse_port: bool,
+    /// TLS configuration for TCP listeners, if we terminate HTTPS ourselves
+    pub(crate) tls: Option<TlsAcceptor>,
+    #[cfg(feature = "acme")]
+    pub(crate) acme: Option<Acme>,
+    /// How long to wait for in-flight requests after a shutdown signal
+    pub(crate) shutdown_timeout: Duration,
+    /// Number of open connections, across all listeners
+    pub(crate) connections: AtomicUsize,
+    pub(crate) methods: AllowedMethods,
+    pub(crate) cors: Option<Cors>,
+    pub(crate) health_path: String,
+    pub(crate) maintenance: Option<Maintenance>,
+    /// Contents to serve for /robots.txt
+    pub(crate) robots: Option<String>,
+    pub(crate) log_format: LogFormat,
+    pub(crate) log_sink: LogSink,
+    /// Which requests to log, raised to VERBOSE by SIGUSR1 and put back by SIGUSR2
+    pub(crate) verbosity: AtomicU8,
+    /// The verbosity from the command line
+    #[cfg(unix)]
+    pub(crate) default_verbosity: u8,
+    pub(crate) trust_request_id: bool,
+    pub(crate) metrics: Metrics,
+    /// Strict-Transport-Security value to send on HTTPS redirects
+    pub(crate) hsts: Option<HeaderValue>,
+    pub(crate) trust_forwarded: bool,
+    /// Header to read the host from in place of Host, when present
+    pub(crate) host_header: Option<HeaderName>,
+    pub(crate) rate_limiter: Option<RateLimiter>,
+    /// Permits for --max-concurrent, one per request being handled
+    pub(crate) concurrency: Option<Arc<Semaphore>>,
+    pub(crate) body_template: Option<String>,
+    /// Delay in seconds before an interstitial page refreshes, if redirects are
+    /// replaced by one
+    pub(crate) interstitial: Option<u64>,
+    /// Whether to also send the location in a Link header
+    pub(crate) link_header: bool,
+    pub(crate) compress: bool,
+    pub(crate) cache_control: Option<HeaderValue>,
+    pub(crate) headers: Vec<CustomHeader>,
+    pub(crate) server_header: Option<HeaderValue>,
+    /// Make sure no Server header is sent, even from --header
+    pub(crate) no_server_header: bool,
+    /// Normalized destination hosts we may redirect to, or any if empty
+    pub(crate) allow_dest: Vec<Vec<u8>>,
+}
+
+/// Where to send a request, before composing the final location
+pub(crate) struct Target<'a> {
+    /// Scheme from the matched rule, overriding --insecure
+    pub(crate) scheme: Option<&'static str>,
+    pub(crate) dest: Cow<'a, str>,
+    /// Path and query to append to the destination, borrowed from the request
+    /// until something needs to change it
+    pub(crate) path: Cow<'a, str>,
+    /// Whether the destination was picked from a weighted split, so the redirect
+    /// must not be cached
+    pub(crate) split: bool,
+    /// Redirect status from the matched rule, overriding --status, or the
+    /// status to respond with if it isn't a redirect
+    pub(crate) status: Option<StatusCode>,
+}
+
+/// The outcome of routing a request on its host alone
+pub(crate) enum HostRoute<'a> {
+    /// A pair, rewrite, or www toggle matched
+    Target(Target<'a>),
+    /// Nothing matched, and --strict-paths rules out trying anything else
+    Refused,
+    /// Nothing matched, so the catch-all or fallbacks may apply
+    Unmatched,
+}
+
+impl App {
+    /// Handle a single request, given the address of the client if known
+    pub async fn handle(
+        self: Arc<Self>,
+        mut req: Request<Body>,
+        remote: Option<SocketAddr>,
+    ) -> Result<Response<Body>, Infallible> {
+        let start = Instant::now();
+        // HTTP/2 clients send the :authority pseudo-header rather than Host
+        if !req.headers().contains_key(HOST) {
+            let authority = req
+                .uri()
+                .authority()
+                .map(|authority| authority.as_str().rsplit('@').next().unwrap_or(""))
+                .and_then(|authority| HeaderValue::from_str(authority).ok());
+            if let Some(authority) = authority {
+                req.headers_mut().insert(HOST, authority);
+            }
+        }
+        let received = SystemTime::now();
+        let head = req.method() == Method::HEAD;
+        let request_id = match req.headers().get(X_REQUEST_ID) {
+            Some(request_id) if self.trust_request_id => request_id.clone(),
+            _ => generate_request_id(),
+        };
+        let mut log = RequestLog {
+            request_id,
+            received,
+            client: self.client_ip(&req, remote),
+            method: req.method(),
+            host: self.host(&req),
+            uri: req.uri(),
+            version: req.version(),
+            referer: req.headers().get(REFERER),
+            user_agent: req.headers().get(USER_AGENT),
+            location: None,
+            status: StatusCode::OK,
+            size: None,
+            fallback: false,
+            elapsed: Duration::ZERO,
+        };
+        // Held until the response is ready, so the request counts against
+        // --max-concurrent for as long as we're working on it
+        let permit = match &self.concurrency {
+            Some(semaphore) => semaphore.clone().try_acquire_owned().map(Some),
+            None => Ok(None),
+        };
+        let mut res = match permit {
+            Err(_) => make_response(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Too many concurrent requests",
+                [(RETRY_AFTER, HeaderValue::from_static("1"))],
+            ),
+            Ok(_permit) => self.handle_inner(&req, remote, &mut log).await,
+        };
+        // Everything from here on applies to every response, however it was made
+        if let Some(error_body) = &self.error_body {
+            let status = res.status();
+            if status.is_client_error() || status.is_server_error() {
+                *res.body_mut() = Body::from(error_body.replace("{status}", status.as_str()));
+            }
+        }
+        let headers = res.headers_mut();
+        headers.insert(X_REQUEST_ID, log.request_id.clone());
+        // Custom headers never replace the ones we set ourselves
+        for CustomHeader(name, value) in &self.headers {
+            if !headers.contains_key(name) {
+                headers.insert(name.clone(), value.clone());
+            }
+        }
+        log.status = res.status();
+        log.size = res.body().size_hint().exact();
+        log.elapsed = start.elapsed();
+        self.log_request(&log);
+        self.metrics.record(res.status());
+        if log.fallback {
+            self.metrics.record_fallback();
+        }
+        let mut res = if head { without_body(res) } else { res };
+        match &self.server_header {
+            Some(server) => {
+                res.headers_mut().insert(SERVER, server.clone());
+            }
+            None if self.no_server_header => {
+                res.headers_mut().remove(SERVER);
+            }
+            None => (),
+        }
+        Ok(res)
+    }
+
+    async fn handle_inner(
+        &self,
+        req: &Request<Body>,
+        remote: Option<SocketAddr>,
+        log: &mut RequestLog<'_>,
+    ) -> Response<Body> {
+        // Challenges are answered before anything else, so that a certificate
+        // can be issued even in maintenance mode
+        #[cfg(feature = "acme")]
+        if let Some(acme) = &self.acme {
+            if let Some(token) = req.uri().path().strip_prefix(ACME_CHALLENGE_PREFIX) {
+                return match acme.proof(token) {
+                    Some(proof) => make_response(StatusCode::OK, proof, []),
+                    None => make_response(StatusCode::NOT_FOUND, "Not found", []),
+                };
+            }
+        }
+        if req.uri().path() == self.health_path {
+            return make_response(StatusCode::OK, "OK", []);
+        }
+        if let Some(maintenance) = &self.maintenance {
+            if maintenance.enabled.load(Ordering::Relaxed) {
+                return make_response(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    format!(
+                        "Down for maintenance, see {}",
+                        String::from_utf8_lossy(maintenance.location.as_bytes())
+                    ),
+                    [
+                        (LOCATION, maintenance.location.clone()),
+                        (RETRY_AFTER, HeaderValue::from(maintenance.retry_after)),
+                    ],
+                );
+            }
+        }
+        if let Some(rate_limiter) = &self.rate_limiter {
+            if let Some(ip) = self.client_ip(req, remote) {
+                if let Err(wait) = rate_limiter.check(ip) {
+                    // Round up, so a client that waits as told is let through
+                    let retry_after = (wait.as_secs() + u64::from(wait.subsec_nanos() > 0)).max(1);
+                    return make_response(
+                        StatusCode::TOO_MANY_REQUESTS,
+                        "Too many requests",
+                        [(RETRY_AFTER, HeaderValue::from(retry_after))],
+                    );
+                }
+            }
+        }
+        if let Some(robots) = &self.robots {
+            if req.uri().path() == "/robots.txt" {
+                return make_response(
+                    StatusCode::OK,
+                    robots.clone(),
+                    [(CONTENT_TYPE, HeaderValue::from_static("text/plain"))],
+                );
+            }
+        }
+        if let Some(cors) = &self.cors {
+            if req.method() == Method::OPTIONS {
+                let mut headers = vec![
+                    (ACCESS_CONTROL_ALLOW_ORIGIN, cors.origin.clone()),
+                    (ACCESS_CONTROL_ALLOW_METHODS, self.methods.allow.clone()),
+                ];
+                if let Some(allow_headers) = &cors.headers {
+                    headers.push((ACCESS_CONTROL_ALLOW_HEADERS, allow_headers.clone()));
+                }
+                return make_response(StatusCode::NO_CONTENT, Body::empty(), headers);
+            }
+        }
+        self.redirect(req, log)
+    }
+
+    /// Write a request to the access log, if the verbosity calls for it
+    fn log_request(&self, log: &RequestLog) {
+        // Successful redirects are only logged in verbose mode, and in quiet mode
+        // we only log server errors and requests missing a host
+        let should_log = match self.verbosity.load(Ordering::Relaxed) {
+            VERBOSE => true,
+            QUIET => log.status.is_server_error() || log.host.map_or(true, HeaderValue::is_empty),
+            _ => log.status.is_client_error() || log.status.is_server_error(),
+        };
+        if should_log {
+            self.log_sink
+                .write_line(&self.log_format.format_request(log));
+        }
+    }
+
+    pub(crate) async fn handle_metrics(
+        self: Arc<Self>,
+        req: Request<Body>,
+        _remote: Option<SocketAddr>,
+    ) -> Result<Response<Body>, Infallible> {
+        Ok(if req.uri().path() == "/metrics" {
+            make_response(
+                StatusCode::OK,
+                self.render_metrics(),
+                [(
+                    CONTENT_TYPE,
+                    HeaderValue::from_static("text/plain; version=0.0.4"),
+                )],
+            )
+        } else if req.uri().path() == "/debug/stats" {
+            make_response(
+                StatusCode::OK,
+                self.render_stats(),
+                [(
+                    CONTENT_TYPE,
+                    HeaderValue::from_static("text/plain; charset=utf-8"),
+                )],
+            )
+        } else {
+            make_response(StatusCode::NOT_FOUND, "Not found", [])
+        })
+    }
+
+    /// Render hits per pair and the overall counters for people rather than
+    /// Prometheus, to make pairs that never match easy to spot. Hits are reset
+    /// when the pairs file is reloaded.
+    pub(crate) fn render_stats(&self) -> String {
+        let mut out = String::new();
+        let rules = self.rules.load();
+        let hits: Vec<u64> = rules
+            .rules
+            .iter()
+            .map(|rule| rule.hits.load(Ordering::Relaxed))
+            .collect();
+        let width = hits
+            .iter()
+            .map(|hits| hits.to_string().len())
+            .max()
+            .unwrap_or(1);
+        for (rule, hits) in rules.rules.iter().zip(hits) {
+            writeln!(out, "{:>width$} {}", hits, rule, width = width).unwrap();
+        }
+        writeln!(out).unwrap();
+        let counters = [
+            ("redirects", &self.metrics.redirects),
+            ("fallbacks", &self.metrics.fallbacks),
+            ("client errors", &self.metrics.client_errors),
+            ("server errors", &self.metrics.server_errors),
+        ];
+        for (name, counter) in counters {
+            writeln!(out, "{}: {}", name, counter.load(Ordering::Relaxed)).unwrap();
+        }
+        out
+    }
+
+    /// Render all counters in the Prometheus text exposition format
+    pub(crate) fn render_metrics(&self) -> String {
+        let mut out = String::new();
+        let counters = [
+            (
+                "redirector_redirects_total",
+                "Total redirect responses",
+                &self.metrics.redirects,
+            ),
+            (
+                "redirector_fallbacks_total",
+                "Requests sent to the fallback destination",
+                &self.metrics.fallbacks,
+            ),
+            (
+                "redirector_client_errors_total",
+                "Total 4xx responses",
+                &self.metrics.client_errors,
+            ),
+            (
+                "redirector_server_errors_total",
+                "Total 5xx responses",
+                &self.metrics.server_errors,
+            ),
+        ];
+        for (name, help, counter) in counters {
+            writeln!(out, "# HELP {} {}", name, help).unwrap();
+            writeln!(out, "# TYPE {} counter", name).unwrap();
+            writeln!(out, "{} {}", name, counter.load(Ordering::Relaxed)).unwrap();
+        }
+
+        writeln!(
+            out,
+            "# HELP redirector_host_hits_total Requests matched per source host"
+        )
+        .unwrap();
+        writeln!(out, "# TYPE redirector_host_hits_total counter").unwrap();
+        let rules = self.rules.load();
+        for rule in &rules.rules {
+            writeln!(
+                out,
+                "redirector_host_hits_total{{host=\"{}\"}} {}",
+                escape_label(&rule.to_string()),
+                rule.hits.load(Ordering::Relaxed)
+            )
+            .unwrap();
+        }
+        out
+    }
+
+    /// Find the first --rewrite matching a normalized host
+    pub(crate) fn rewrite(&self, host: &[u8]) -> Option<Destination> {
+        let host = std::str::from_utf8(host).ok()?;
+        self.rewrites.iter().find_map(|rewrite| rewrite.apply(host))
+    }
+
+    /// Redirect between www.host and host when --strip-www or --add-www is set
+    pub(crate) fn toggle_www(&self, host: &[u8]) -> Option<Destination> {
+        let host = std::str::from_utf8(host).ok()?;
+        let toggled = match self.www? {
+            WwwMode::Strip => host.strip_prefix("www.")?.to_owned(),
+            WwwMode::Add if host.starts_with("www.") => return None,
+            WwwMode::Add => format!("www.{}", host),
+        };
+        Some(Destination {
+            scheme: None,
+            host: toggled,
+        })
+    }
+
+    /// Cache-Control for a redirect, defaulting to long-lived caching for
+    /// permanent redirects and none for temporary ones
+    pub(crate) fn cache_control(&self, status: StatusCode) -> HeaderValue {
+        match &self.cache_control {
+            Some(cache_control) => cache_control.clone(),
+            None if status == StatusCode::MOVED_PERMANENTLY
+                || status == StatusCode::PERMANENT_REDIRECT =>
+            {
+                HeaderValue::from_static("max-age=31536000")
+            }
+            None => HeaderValue::from_static("no-store"),
+        }
+    }
+
+    /// The host to route on, from --host-header if the request has it
+    pub(crate) fn host<'a>(&self, req: &'a Request<Body>) -> Option<&'a HeaderValue> {
+        self.host_header
+            .as_ref()
+            .and_then(|name| req.headers().get(name))
+            .or_else(|| req.headers().get(HOST))
+    }
+
+    /// The client's IP address, from X-Forwarded-For if trusted or else the
+    /// connection itself
+    pub(crate) fn client_ip(
+        &self,
+        req: &Request<Body>,
+        remote: Option<SocketAddr>,
+    ) -> Option<IpAddr> {
+        if self.trust_forwarded {
+            let forwarded = req
+                .headers()
+                .get("x-forwarded-for")
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.split(',').next())
+                .and_then(|value| value.trim().parse().ok());
+            if forwarded.is_some() {
+                return forwarded;
+            }
+        }
+        remote.map(|remote| remote.ip())
+    }
+
+    /// Find where to send a request, or None if no rule or fallback applies.
+    ///
+    /// In --upgrade-only mode every request goes to the same host. Otherwise
+    /// the pairs are checked in order, then rewrites, www toggling, the domain
+    /// fallbacks, the * catch-all, and finally the fallback. A request with an empty host only
+    /// gets the catch-all or fallback, and with --strict-paths one for a host with
+    /// path pairs gets nothing beyond the pairs.
+    pub(crate) fn route<'a>(
+        &self,
+        rules: &'a Rules,
+        req: &'a Request<Body>,
+        host: &HeaderValue,
+        host_key: &[u8],
+        log: &mut RequestLog,
+    ) -> Option<Target<'a>> {
+        if !host_key.is_empty() {
+            match self.route_host(rules, req, host, host_key) {
+                HostRoute::Target(target) => return Some(target),
+                HostRoute::Refused => return None,
+                HostRoute::Unmatched => (),
+            }
+            let domain_fallback = self
+                .domain_fallbacks
+                .iter()
+                .find(|domain_fallback| domain_fallback.matches(host_key));
+            if let Some(domain_fallback) = domain_fallback {
+                log.fallback = true;
+                return Some(self.fallback_target(
+                    &domain_fallback.dest,
+                    Cow::Owned(domain_fallback.dest.host.clone()),
+                    req,
+                ));
+            }
+        }
+        // A *=dest pair is a catch-all like --fallback, but is treated like any
+        // other pair and so takes priority over it
+        if let Some(rule) = rules.catch_all() {
+            rule.hits.fetch_add(1, Ordering::Relaxed);
+            let dest = rule.choose();
+            return Some(Target {
+                scheme: dest.scheme,
+                dest: Cow::Borrowed(&dest.host),
+                path: Cow::Borrowed(path_and_query(req.uri())),
+                split: rule.split.is_some(),
+                status: rule.status,
+            });
+        }
+        let fallback = rules.fallback.as_ref()?;
+        log.fallback = true;
+        Some(self.fallback_target(fallback, Cow::Borrowed(&fallback.host), req))
+    }
+
+    pub(crate) fn fallback_target<'a>(
+        &self,
+        fallback: &Destination,
+        dest: Cow<'a, str>,
+        req: &'a Request<Body>,
+    ) -> Target<'a> {
+        Target {
+            scheme: fallback.scheme,
+            dest,
+            // A fallback with a scheme is a full URL, used verbatim
+            path: Cow::Borrowed(if fallback.scheme.is_some() {
+                ""
+            } else {
+                path_and_query(req.uri())
+            }),
+            split: false,
+            status: self.fallback_status,
+        }
+    }
+
+    /// Find where to send a request based on its host, before resorting to the
+    /// catch-all or fallback
+    pub(crate) fn route_host<'a>(
+        &self,
+        rules: &'a Rules,
+        req: &'a Request<Body>,
+        host: &HeaderValue,
+        host_key: &[u8],
+    ) -> HostRoute<'a> {
+        if self.upgrade_only {
+            return HostRoute::Target(Target {
+                scheme: Some("https"),
+                dest: String::from_utf8_lossy(strip_port(host.as_bytes()))
+                    .into_owned()
+                    .into(),
+                path: Cow::Borrowed(path_and_query(req.uri())),
+                split: false,
+                status: None,
+            });
+        }
+        // Port-qualified pairs like example.com:8080 take priority over bare hosts
+        let authority_key = match port(host.as_bytes()) {
+            Some(_) => normalize_host(host.as_bytes()),
+            None => None,
+        };
+        let uri = req.uri();
+        if let Some(rule) =
+            rules.lookup(host_key, authority_key.as_deref(), uri.path(), uri.query())
+        {
+            rule.hits.fetch_add(1, Ordering::Relaxed);
+            let dest = rule.choose();
+            return HostRoute::Target(Target {
+                scheme: dest.scheme,
+                dest: Cow::Borrowed(&dest.host),
+                path: rule.rewrite(req.uri()),
+                split: rule.split.is_some(),
+                status: rule.status,
+            });
+        }
+        if self.strict_paths && rules.path_hosts.contains(host_key) {
+            return HostRoute::Refused;
+        }
+        if let Some(dest) = self.rewrite(host_key).or_else(|| self.toggle_www(host_key)) {
+            return HostRoute::Target(Target {
+                scheme: dest.scheme,
+                dest: Cow::Owned(dest.host),
+                path: Cow::Borrowed(path_and_query(req.uri())),
+                split: false,
+                status: None,
+            });
+        }
+        HostRoute::Unmatched
+    }
+
+    /// Would redirecting to this location send the client right back to the
+    /// URL it requested?
+    pub(crate) fn is_loop(
+        &self,
+        req: &Request<Body>,
+        host: &HeaderValue,
+        scheme: &str,
+        location: &str,
+    ) -> bool {
+        let inbound_scheme = match forwarded_proto(req) {
+            Some(proto) if self.trust_forwarded => proto,
+            _ if req.extensions().get::<InboundTls>().is_some() => "https",
+            _ => "http",
+        };
+        if !inbound_scheme.eq_ignore_ascii_case(scheme) {
+            return false;
+        }
+        let rest = &location[scheme.len() + "://".len()..];
+        let (authority, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+        let inbound_path = path_and_query(req.uri());
+        authority.as_bytes().eq_ignore_ascii_case(host.as_bytes())
+            && (path == inbound_path || (path.is_empty() && inbound_path == "/"))
+    }
+
+    pub(crate) fn redirect(&self, req: &Request<Body>, log: &mut RequestLog) -> Response<Body> {
+        // The URI ends up in the Location header, so don't pass on anything huge
+        let uri_length = req
+            .uri()
+            .path_and_query()
+            .map_or(0, |path_and_query| path_and_query.as_str().len());
+        if uri_length > self.max_uri_length {
+            return make_response(StatusCode::URI_TOO_LONG, "URI too long", []);
+        }
+        if !self.methods.methods.contains(req.method()) {
+            return make_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "Method not allowed",
+                [(ALLOW, self.methods.allow.clone())],
+            );
+        }
+        let rules = self.rules.load();
+        // An empty Host header is treated the same as a missing one, which can
+        // only be sent to the catch-all or fallback
+        let empty_host = HeaderValue::from_static("");
+        let host = match self.host(req).filter(|host| !host.is_empty()) {
+            Some(host) => host,
+            None if rules.catch_all().is_some() || rules.fallback.is_some() => &empty_host,
+            None => return make_response(StatusCode::BAD_REQUEST, "Missing host header", []),
+        };
+        if !validate_host(host.as_bytes()) {
+            return make_response(StatusCode::BAD_REQUEST, "Invalid host header", []);
+        }
+        let host_key = match normalize_host(strip_port(host.as_bytes())) {
+            Some(host_key) => host_key,
+            None => return make_response(StatusCode::BAD_REQUEST, "Invalid host header", []),
+        };
+        // Upgrading redirects to the request's own host, which can't be put in a
+        // URL without mangling it if it isn't UTF-8
+        if self.upgrade_only && std::str::from_utf8(host.as_bytes()).is_err() {
+            return make_response(StatusCode::BAD_REQUEST, "Invalid host header", []);
+        }
+        let Target {
+            scheme,
+            dest,
+            mut path,
+            split,
+            status,
+        } = match self.route(&rules, req, host, &host_key, log) {
+            Some(target) => target,
+            None => return make_response(self.unknown_host_status, "Unsupported hostname", []),
+        };
+        if let Some(status) = status.filter(|status| !status.is_redirection()) {
+            let body = if status == StatusCode::NO_CONTENT {
+                Body::empty()
+            } else {
+                Body::from(status.canonical_reason().unwrap_or(""))
+            };
+            return make_response(status, body, []);
+        }
+        if self.strip_fragment {
+            if let Some(idx) = fragment_start(&path) {
+                truncate(&mut path, idx);
+            }
+        }
+        if self.strip_query {
+            if let Some(idx) = path.find('?') {
+                truncate(&mut path, idx);
+            }
+        }
+        if self.collapse_slashes {
+            collapse_slashes(&mut path);
+        }
+        self.trailing_slash.apply(&mut path);
+        let scheme = scheme.unwrap_or(
+            if self.insecure || self.insecure_hosts.contains(&host_key) {
+                "http"
+            } else {
+                "https"
+            },
+        );
+        let dest = match port(host.as_bytes()) {
+            Some(port) if self.preserve_port && !is_default_port(scheme, port) => {
+                Cow::Owned(add_port(&dest, port))
+            }
+            _ => dest,
+        };
+        // X-Forwarded-Proto is set by the proxy in front of us, and is only
+        // honored when the operator has told us the proxy is trusted.
+        if self.trust_forwarded
+            && forwarded_proto(req).map_or(false, |proto| proto.eq_ignore_ascii_case(scheme))
+            && dest
+                .as_bytes()
+                .eq_ignore_ascii_case(strip_port(host.as_bytes()))
+        {
+            return make_response(StatusCode::OK, format!("Already using {}", scheme), []);
+        }
+        // Built up directly rather than with format!, since this is the hot path
+        let rest = if self.drop_path { "" } else { &path };
+        let mut location =
+            String::with_capacity(scheme.len() + "://".len() + dest.len() + rest.len());
+        location.push_str(scheme);
+        location.push_str("://");
+        location.push_str(&dest);
+        location.push_str(rest);
+        if !self.allow_dest.is_empty() {
+            let dest_host = strip_port(dest.split('/').next().unwrap_or("").as_bytes());
+            let allowed = normalize_host(dest_host)
+                .map_or(false, |dest_host| self.allow_dest.contains(&dest_host));
+            if !allowed {
+                self.log_sink.write_line(&format!(
+                    "[{}] Refusing to redirect to {}, destination is not in --allow-dest",
+                    String::from_utf8_lossy(log.request_id.as_bytes()),
+                    location
+                ));
+                log.location = HeaderValue::from_str(&location).ok();
+                return make_response(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Destination not allowed",
+                    [],
+                );
+            }
+        }
+        if self.is_loop(req, host, scheme, &location) {
+            log.location = HeaderValue::from_str(&location).ok();
+            return make_response(StatusCode::LOOP_DETECTED, "Redirect loop detected", []);
+        }
+        // Browsers cache permanent redirects, which would pin them to one side of
+        // a weighted split, unless the pair set its own status
+        let status = match (status, self.status) {
+            (Some(status), _) => status,
+            (None, StatusCode::MOVED_PERMANENTLY) if split => StatusCode::FOUND,
+            (None, StatusCode::PERMANENT_REDIRECT) if split => StatusCode::TEMPORARY_REDIRECT,
+            (None, status) => status,
+        };
+        let status = if self.interstitial.is_some() {
+            StatusCode::OK
+        } else {
+            status
+        };
+        let mut headers = vec![(CACHE_CONTROL, self.cache_control(status))];
+        if self.link_header {
+            let link = format!(
+                "<{}>; rel=\"alternate\"",
+                location.replace('<', "%3C").replace('>', "%3E")
+            );
+            if let Ok(link) = HeaderValue::from_str(&link) {
+                headers.push((LINK, link));
+            }
+        }
+        if scheme == "https" {
+            if let Some(hsts) = &self.hsts {
+                headers.push((STRICT_TRANSPORT_SECURITY, hsts.clone()));
+            }
+        }
+        let page = match (self.interstitial, &self.body_template) {
+            (Some(delay), _) => Some(interstitial_page(delay, &location)),
+            (None, Some(template)) => Some(template.replace("{location}", &escape_html(&location))),
+            (None, None) => None,
+        };
+        let body = match page {
+            Some(body) => {
+                headers.push((
+                    CONTENT_TYPE,
+                    HeaderValue::from_static("text/html; charset=utf-8"),
+                ));
+                if self.compress && body.len() > COMPRESS_THRESHOLD && accepts_gzip(req) {
+                    match gzip(body.as_bytes()) {
+                        Ok(compressed) => {
+                            headers.push((CONTENT_ENCODING, HeaderValue::from_static("gzip")));
+                            headers.push((VARY, HeaderValue::from_static("accept-encoding")));
+                            Body::from(compressed)
+                        }
+                        Err(e) => {
+                            self.log_sink.write_line(&format!(
+                                "[{}] Unable to compress response body: {:?}",
+                                String::from_utf8_lossy(log.request_id.as_bytes()),
+                                e
+                            ));
+                            Body::from(body)
+                        }
+                    }
+                } else {
+                    Body::from(body)
+                }
+            }
+            None => Body::from("Redirecting"),
+        };
+        // The location is handed over to the header as is, rather than copied
+        let location = match HeaderValue::from_maybe_shared(Bytes::from(location)) {
+            Ok(location) => location,
+            Err(e) => {
+                return make_response(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Unable to convert location to HTTP header value: {:?}", e),
+                    [],
+                )
+            }
+        };
+        if self.interstitial.is_none() {
+            headers.insert(0, (LOCATION, location.clone()));
+        }
+        log.location = Some(location);
+        make_response(status, body, headers)
+    }
+}
+
+pub(crate) const X_REQUEST_ID: &str = "x-request-id";
+
+/// A short random hex ID for correlating a request across logs and systems
+pub(crate) fn generate_request_id() -> HeaderValue {
+    const HEX: &[u8; 16] = b"0123456789abcdef";
+    // Written out by hand into a buffer on the stack, since this runs for every
+    // request
+    let mut id = [0; 16];
+    let mut n = rand::random::<u64>();
+    for digit in id.iter_mut().rev() {
+        *digit = HEX[(n & 0xf) as usize];
+        n >>= 4;
+    }
+    HeaderValue::from_bytes(&id).expect("hex digits are a valid header value")
+}
+
+/// The path and query of a request, without the scheme and authority of an
+/// absolute-form URI
+pub(crate) fn path_and_query(uri: &Uri) -> &str {
+    uri.path_and_query()
+        .map_or("/", |path_and_query| path_and_query.as_str())
+}
+
+/// The scheme the client originally used, according to the first
+/// X-Forwarded-Proto value
+pub(crate) fn forwarded_proto(req: &Request<Body>) -> Option<&str> {
+    let value = req.headers().get("x-forwarded-proto")?.to_str().ok()?;
+    value.split(',').next().map(str::trim)
+}
+
+impl App {
+    /// An App with the default settings for these pairs, as if they were given on
+    /// the command line with no other flags.
+    ///
+    /// This is for driving requests through [`App::handle`] in-process, without
+    /// binding a socket.
+    pub fn new(
+        pairs: Vec<DomainPair>,
+        fallback: Option<String>,
+        insecure: bool,
+    ) -> anyhow::Result<Arc<App>> {
+        let mut opt = Opt::try_parse_from(["redirector"])?;
+        opt.pairs = pairs;
+        opt.fallback = fallback;
+        opt.insecure = insecure;
+        let rules = load_rules(&opt.pairs, None, opt.fallback.clone(), opt.on_duplicate)?;
+        Ok(Arc::new(App::from_opt(opt, rules)?))
+    }
+
+    /// An App configured from command line arguments, starting with the program
+    /// name, for trying out other flags in-process. The pairs file is read, but
+    /// not a config file, stdin, or --pairs-url.
+    pub fn from_args<I, T>(args: I) -> anyhow::Result<Arc<App>>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<std::ffi::OsString> + Clone,
+    {
+        let opt = Opt::try_parse_from(args)?;
+        let rules = load_rules(
+            &opt.pairs,
+            opt.pairs_file.as_deref(),
+            opt.fallback.clone(),
+            opt.on_duplicate,
+        )?;
+        Ok(Arc::new(App::from_opt(opt, rules)?))
+    }
+
+    pub(crate) fn from_opt(opt: Opt, rules: Rules) -> anyhow::Result<Self> {
+        if opt.rate_limit == Some(0) {
+            anyhow::bail!("--rate-limit must be at least 1");
+        }
+        if opt.max_concurrent == Some(0) {
+            anyhow::bail!("--max-concurrent must be at least 1");
+        }
+        let allow_dest = opt
+            .allow_dest
+            .iter()
+            .map(|domain| {
+                normalize_host(domain.as_bytes())
+                    .with_context(|| format!("Invalid --allow-dest domain name {}", domain))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let insecure_hosts = opt
+            .insecure_host
+            .iter()
+            .map(|host| {
+                normalize_host(host.as_bytes())
+                    .with_context(|| format!("Invalid --insecure-host name {}", host))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let log_sink = match &opt.log_file {
+            Some(path) => LogSink::open(path, opt.log_max_size)?,
+            None => LogSink::Stderr,
+        };
+        let verbosity = if opt.verbose {
+            VERBOSE
+        } else if opt.quiet {
+            QUIET
+        } else {
+            NORMAL
+        };
+        let tls = match (&opt.tls_cert, &opt.tls_key) {
+            (Some(cert), Some(key)) => Some(load_tls(cert, key, opt.http2)?),
+            _ => None,
+        };
+        #[cfg(feature = "acme")]
+        let acme = opt.acme_email.as_ref().map(|email| {
+            Acme::new(
+                opt.acme_domain.clone(),
+                email.clone(),
+                opt.acme_dir.clone(),
+                opt.acme_bind,
+                opt.acme_staging,
+                opt.http2,
+            )
+        });
+        let robots = match &opt.robots_file {
+            Some(path) => Some(
+                std::fs::read_to_string(path)
+                    .with_context(|| format!("Unable to read robots file {}", path.display()))?,
+            ),
+            None if opt.robots => Some("User-agent: *\nDisallow: /\n".to_owned()),
+            None => None,
+        };
+        Ok(App {
+            rules: ArcSwap::from_pointee(rules),
+            rewrites: opt.rewrite,
+            insecure: opt.insecure,
+            insecure_hosts,
+            status: match opt.status {
+                Some(RedirectStatus(status)) => status,
+                None if opt.temporary => StatusCode::TEMPORARY_REDIRECT,
+                None => StatusCode::PERMANENT_REDIRECT,
+            },
+            domain_fallbacks: opt.domain_fallback,
+            fallback_status: opt.fallback_status.map(|RedirectStatus(status)| status),
+            unknown_host_status: opt.unknown_host_status.0,
+            error_body: opt.error_body,
+            upgrade_only: opt.upgrade_only,
+            drop_path: opt.drop_path,
+            strict_paths: opt.strict_paths,
+            strip_query: opt.strip_query,
+            strip_fragment: opt.strip_fragment,
+            collapse_slashes: opt.collapse_slashes,
+            trailing_slash: opt.trailing_slash,
+            preserve_port: opt.preserve_port,
+            www: if opt.strip_www {
+                Some(WwwMode::Strip)
+            } else if opt.add_www {
+                Some(WwwMode::Add)
+            } else {
+                None
+            },
+            request_timeout: opt.request_timeout.map(Duration::from_secs),
+            max_uri_length: opt.max_uri_length,
+            http2: opt.http2,
+            keep_alive: opt.keep_alive.map(Duration::from_secs),
+            backlog: opt.backlog,
+            reuse_port: opt.reuse_port,
+            tls,
+            #[cfg(feature = "acme")]
+            acme,
+            shutdown_timeout: Duration::from_secs(opt.shutdown_timeout),
+            connections: AtomicUsize::new(0),
+            methods: opt.methods,
+            cors: if opt.cors {
+                Some(Cors {
+                    origin: opt.cors_origin,
+                    headers: opt.cors_headers,
+                })
+            } else {
+                None
+            },
+            health_path: opt.health_path,
+            maintenance: opt.maintenance.map(|location| Maintenance {
+                location,
+                retry_after: opt.maintenance_retry_after,
+                enabled: AtomicBool::new(
+                    opt.maintenance_file.as_deref().map_or(true, Path::exists),
+                ),
+                file: opt.maintenance_file,
+            }),
+            robots,
+            log_format: opt.log_format,
+            log_sink,
+            verbosity: AtomicU8::new(verbosity),
+            #[cfg(unix)]
+            default_verbosity: verbosity,
+            trust_request_id: opt.trust_request_id,
+            metrics: Metrics::default(),
+            trust_forwarded: opt.trust_forwarded,
+            host_header: opt.host_header.filter(|name| *name != HOST),
+            rate_limiter: opt.rate_limit.map(RateLimiter::new),
+            concurrency: opt
+                .max_concurrent
+                .map(|permits| Arc::new(Semaphore::new(permits as usize))),
+            body_template: opt.body_template,
+            interstitial: opt.interstitial,
+            link_header: opt.link_header,
+            compress: opt.compress,
+            cache_control: opt.cache_control,
+            headers: opt.header,
+            server_header: opt.server_header,
+            no_server_header: opt.no_server_header,
+            allow_dest,
+            hsts: opt.hsts.map(|max_age| {
+                let value = if opt.hsts_no_subdomains {
+                    format!("max-age={}", max_age)
+                } else {
+                    format!("max-age={}; includeSubDomains", max_age)
+                };
+                HeaderValue::from_str(&value).expect("HSTS header value is always valid")
+            }),
+        })
+    }
+}
@@ -0,0 +1,643 @@
+//! Command line flags, the --config file, and the small option types they parse into
+
+use std::{
+    borrow::Cow,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::atomic::AtomicBool,
+};
+
+use anyhow::Context;
+use hyper::{
+    header::{HeaderName, HeaderValue},
+    Method, StatusCode, Uri,
+};
+
+use crate::{
+    log::LogFormat,
+    pairs::{DomainFallback, DomainPair, Rewrite},
+    rules::{OnDuplicate, Rules},
+};
+
+/// Crate version plus the git commit it was built from
+pub(crate) const VERSION: &str = concat!(env!("CARGO_PKG_VERSION"), " (", env!("GIT_COMMIT"), ")");
+
+#[derive(clap::Parser)]
+#[clap(version = VERSION)]
+pub(crate) struct Opt {
+    /// TOML config file, with command line flags taking precedence. ${VAR} in
+    /// bind, fallback, and pairs values is replaced with environment variables
+    #[clap(long)]
+    pub(crate) config: Option<PathBuf>,
+    /// Redirect to insecure HTTP instead of HTTPS
+    #[clap(long)]
+    pub(crate) insecure: bool,
+    /// Redirect requests for this host to insecure HTTP, while other hosts use
+    /// HTTPS unless --insecure is given. May be given multiple times
+    #[clap(long)]
+    pub(crate) insecure_host: Vec<String>,
+    /// Use a temporary (307) redirect instead of a permanent (308) one
+    #[clap(long, conflicts_with = "status")]
+    pub(crate) temporary: bool,
+    /// Status code to use for redirects: 301, 302, 303, 307, or 308
+    #[clap(long)]
+    pub(crate) status: Option<RedirectStatus>,
+    /// Source<->dest pairs of domain names, e.g. example.com=www.example.com
+    ///
+    /// A source of *.example.com matches any single-label subdomain of example.com,
+    /// and a source of example.com:8080 only matches requests for that port.
+    /// The dest may include an http:// or https:// prefix to override --insecure.
+    /// A source with a path, like example.com/old-docs=docs.example.com/new or
+    /// /old-docs=docs.example.com, matches by path prefix, rewriting the
+    /// matched prefix to the dest path when one is given.
+    /// A source of * matches any host without a more specific pair or rewrite,
+    /// taking priority over --fallback. Otherwise pairs are checked in the order
+    /// given and the first match wins, except that example.com:8080 beats a pair
+    /// for the bare example.com. A prefix like 302|example.com=new.com overrides
+    /// --status for that pair. A dest like 90%new.com,10%old.com picks between
+    /// destinations at random with those weights, using a temporary redirect.
+    /// A source like a.com,b.com,c.com=new.com is shorthand for a pair for each.
+    /// A source like example.com?legacy=1=old.com, or example.com?legacy=old.com
+    /// for any value, only matches requests with that query parameter, so it
+    /// must come before any pair for the same host without one (see --strict).
+    /// A dest of 410, like old.example.com=410, responds with 410 Gone instead of
+    /// redirecting, so that crawlers drop the URLs. Similarly, a dest of 204, like
+    /// beacon.example.com=204, responds with 204 No Content and no Location, to
+    /// acknowledge requests without sending them anywhere.
+    pub(crate) pairs: Vec<DomainPair>,
+    /// File containing newline-separated source=dest pairs, with optional # comments,
+    /// reloaded on SIGHUP
+    #[clap(long)]
+    pub(crate) pairs_file: Option<PathBuf>,
+    /// What to do when a source is given more than once: error, or keep the
+    /// first or last pair. The last pair keeps the first one's place in the
+    /// evaluation order
+    #[clap(long, default_value = "error")]
+    pub(crate) on_duplicate: OnDuplicate,
+    /// Fetch a JSON array of {"source": ..., "dest": ...} pairs from this plain
+    /// HTTP URL before starting
+    #[clap(long)]
+    pub(crate) pairs_url: Option<Uri>,
+    /// Retry a failed --pairs-url fetch this many times before giving up,
+    /// waiting twice as long after each attempt starting from one second
+    #[clap(long, default_value = "0")]
+    pub(crate) startup_retries: u32,
+    /// Read newline-separated source=dest pairs from stdin before starting
+    #[clap(long)]
+    pub(crate) pairs_stdin: bool,
+    /// Regex rewrite of the host, e.g. '^(\w+)\.old\.com$=$1.new.com', checked
+    /// in order after the source<->dest pairs
+    #[clap(long)]
+    pub(crate) rewrite: Vec<Rewrite>,
+    /// Optional default domain destination when no other domain provided, including
+    /// no * pair. A full URL like https://example.com/gone is used verbatim,
+    /// without appending the request path
+    #[clap(long)]
+    pub(crate) fallback: Option<String>,
+    /// Fallback for unmatched subdomains of a domain, e.g. blog.com=blog.com sends
+    /// any *.blog.com host without a pair to blog.com. May be given multiple
+    /// times, with the first match winning, and all are checked before the *
+    /// catch-all and --fallback
+    #[clap(long)]
+    pub(crate) domain_fallback: Vec<DomainFallback>,
+    /// Status code for redirects to the fallback, defaulting to --status
+    #[clap(long)]
+    pub(crate) fallback_status: Option<RedirectStatus>,
+    /// Status code for hosts with no matching pair or fallback, must be 4xx.
+    ///
+    /// 400 suits a host that was never valid here, and 404 one that is simply
+    /// unknown. Prefer 421 Misdirected Request when clients may reuse a TLS or
+    /// HTTP/2 connection for other hosts (e.g. behind a proxy that coalesces
+    /// connections), since it tells them to retry on a fresh connection.
+    #[clap(long, default_value = "400")]
+    pub(crate) unknown_host_status: ClientErrorStatus,
+    /// Response body for every 4xx and 5xx response, such as a missing or unknown
+    /// host or a rate limited request, in place of a message describing the
+    /// problem. {status} is replaced by the status code, and an empty body is
+    /// allowed
+    #[clap(long)]
+    pub(crate) error_body: Option<String>,
+    /// Ignore all pairs and redirect every request to HTTPS on the same host and
+    /// path, e.g. for a listener on port 80. No pairs or fallback are needed, and
+    /// the port is dropped from the host unless --preserve-port is given
+    #[clap(long, visible_alias = "https-upgrade")]
+    pub(crate) upgrade_only: bool,
+    /// Redirect to the root of the destination, dropping the path and query
+    #[clap(long)]
+    pub(crate) drop_path: bool,
+    /// Only redirect the paths given for a host with path pairs, like
+    /// old.com/login=new.com/login, responding with --unknown-host-status for any
+    /// other path instead of trying rewrites, fallbacks, or the catch-all. A pair
+    /// for the whole host still applies to paths without their own pair, and
+    /// --drop-path only affects the destination, not which paths match
+    #[clap(long)]
+    pub(crate) strict_paths: bool,
+    /// Remove the query string from the path appended to the destination
+    #[clap(long)]
+    pub(crate) strip_query: bool,
+    /// Remove any fragment from the path appended to the destination, including
+    /// a percent-encoded one (%23) in the path
+    #[clap(long)]
+    pub(crate) strip_fragment: bool,
+    /// Collapse repeated slashes in the path appended to the destination, e.g.
+    /// /a//b to /a/b, leaving the query string untouched
+    #[clap(long)]
+    pub(crate) collapse_slashes: bool,
+    /// Trailing slash handling for the redirect path: add, strip, or keep
+    #[clap(long, default_value = "keep")]
+    pub(crate) trailing_slash: TrailingSlash,
+    /// Keep a non-default port from the Host header on the destination
+    #[clap(long)]
+    pub(crate) preserve_port: bool,
+    /// Redirect www.example.com to example.com when no pair matches.
+    /// Mutually exclusive with --add-www
+    #[clap(long, conflicts_with = "add-www")]
+    pub(crate) strip_www: bool,
+    /// Redirect example.com to www.example.com when no pair matches
+    #[clap(long)]
+    pub(crate) add_www: bool,
+    /// Seconds to wait for a client to send its HTTP/1 request headers before
+    /// closing the connection
+    #[clap(long)]
+    pub(crate) request_timeout: Option<u64>,
+    /// Longest request path and query to redirect, others get 414 URI Too Long
+    #[clap(long, default_value = "8192")]
+    pub(crate) max_uri_length: usize,
+    /// Comma-separated HTTP methods to redirect, others get 405 Method Not Allowed
+    #[clap(long, default_value = "GET,HEAD")]
+    pub(crate) methods: AllowedMethods,
+    /// Answer OPTIONS preflight requests with 204 No Content and CORS headers
+    /// instead of redirecting
+    #[clap(long)]
+    pub(crate) cors: bool,
+    /// Access-Control-Allow-Origin for --cors
+    #[clap(long, default_value = "*")]
+    pub(crate) cors_origin: HeaderValue,
+    /// Access-Control-Allow-Headers for --cors, e.g. "Content-Type, Authorization"
+    #[clap(long, requires = "cors")]
+    pub(crate) cors_headers: Option<HeaderValue>,
+    /// Serve a /robots.txt disallowing all crawling
+    #[clap(long)]
+    pub(crate) robots: bool,
+    /// Serve /robots.txt with the contents of this file
+    #[clap(long, conflicts_with = "robots")]
+    pub(crate) robots_file: Option<PathBuf>,
+    /// Respond to everything but health checks with 503 Service Unavailable,
+    /// pointing to this status page URL
+    #[clap(long)]
+    pub(crate) maintenance: Option<HeaderValue>,
+    /// Seconds for the Retry-After header in maintenance mode
+    #[clap(long, default_value = "3600")]
+    pub(crate) maintenance_retry_after: u64,
+    /// Only be in maintenance mode while this file exists, checked every second,
+    /// so it can be switched on and off without a restart
+    #[clap(long, requires = "maintenance")]
+    pub(crate) maintenance_file: Option<PathBuf>,
+    /// Path that responds with 200 OK for health checks instead of redirecting
+    #[clap(long, default_value = "/healthz")]
+    pub(crate) health_path: String,
+    /// Maximum requests per minute from a single client IP
+    #[clap(long)]
+    pub(crate) rate_limit: Option<u32>,
+    /// Maximum requests to handle at once, responding with 503 Service
+    /// Unavailable to any beyond that
+    #[clap(long)]
+    pub(crate) max_concurrent: Option<u32>,
+    /// Write access logs to this file instead of stderr
+    #[clap(long)]
+    pub(crate) log_file: Option<PathBuf>,
+    /// Rotate the log file once it reaches this many bytes, keeping one old file
+    /// with a .1 suffix
+    #[clap(long, requires = "log-file")]
+    pub(crate) log_max_size: Option<u64>,
+    /// Log every request, not just failed ones. SIGUSR1 turns this on and SIGUSR2
+    /// turns it back off
+    #[clap(short, long)]
+    pub(crate) verbose: bool,
+    /// Only log server errors and requests without a host
+    #[clap(short, long, conflicts_with = "verbose")]
+    pub(crate) quiet: bool,
+    /// Reuse an inbound X-Request-Id header instead of generating a new ID
+    #[clap(long)]
+    pub(crate) trust_request_id: bool,
+    /// Format for access logs: text, json, or clf for the Apache combined log
+    /// format read by tools like GoAccess and AWStats
+    #[clap(long, default_value = "text")]
+    pub(crate) log_format: LogFormat,
+    /// Add a Strict-Transport-Security header with this max-age to HTTPS redirects
+    #[clap(long)]
+    pub(crate) hsts: Option<u64>,
+    /// Omit includeSubDomains from the Strict-Transport-Security header
+    #[clap(long, requires = "hsts")]
+    pub(crate) hsts_no_subdomains: bool,
+    /// Trust the X-Forwarded-Proto header to avoid redirecting clients already on the
+    /// destination scheme, and X-Forwarded-For for rate limiting. Only enable this
+    /// behind a trusted proxy, since clients can otherwise spoof the headers.
+    #[clap(long)]
+    pub(crate) trust_forwarded: bool,
+    /// Header to match pairs against instead of Host, e.g. X-Forwarded-Host,
+    /// falling back to Host when it's missing. Only use this behind a trusted proxy
+    #[clap(long)]
+    pub(crate) host_header: Option<HeaderName>,
+    /// Cache-Control header for redirects, defaulting to max-age=31536000 for
+    /// permanent redirects and no-store for temporary ones
+    #[clap(long)]
+    pub(crate) cache_control: Option<HeaderValue>,
+    /// Extra response header, e.g. "X-Robots-Tag: noindex". May be given multiple times
+    #[clap(long)]
+    pub(crate) header: Vec<CustomHeader>,
+    /// Server header to send on every response
+    #[clap(long)]
+    pub(crate) server_header: Option<HeaderValue>,
+    /// Never send a Server header
+    #[clap(long, conflicts_with = "server-header")]
+    pub(crate) no_server_header: bool,
+    /// Also send the destination as Link: <url>; rel="alternate", for clients
+    /// that follow that instead of Location
+    #[clap(long)]
+    pub(crate) link_header: bool,
+    /// Gzip large response bodies for clients that accept it
+    #[clap(long)]
+    pub(crate) compress: bool,
+    /// HTML response body for redirects, with {location} replaced by the destination
+    #[clap(long)]
+    pub(crate) body_template: Option<String>,
+    /// Respond with a 200 OK "you are leaving" page that refreshes to the
+    /// destination after this many seconds, instead of redirecting
+    #[clap(long, conflicts_with = "body-template")]
+    pub(crate) interstitial: Option<u64>,
+    /// Only redirect to this destination domain, responding with 500 Internal Server
+    /// Error for any other. May be given multiple times
+    #[clap(long)]
+    pub(crate) allow_dest: Vec<String>,
+    /// Speak HTTP/2 with prior knowledge (h2c) instead of HTTP/1.1, for use behind
+    /// a proxy that connects over HTTP/2
+    #[clap(long)]
+    pub(crate) http2: bool,
+    /// Seconds between keep-alive probes on idle connections, using TCP keepalive
+    /// for HTTP/1.1 and PING frames for HTTP/2. 0 disables HTTP/1.1 keep-alive
+    #[clap(long)]
+    pub(crate) keep_alive: Option<u64>,
+    /// Seconds to wait for in-flight requests to finish after a shutdown signal
+    /// before exiting anyway
+    #[clap(long, default_value = "30")]
+    pub(crate) shutdown_timeout: u64,
+    /// Validate this config file on its own, print a summary, and exit
+    #[clap(long, conflicts_with = "config")]
+    pub(crate) validate_config: Option<PathBuf>,
+    /// Fail at startup if any exact pair overlaps a wildcard pair, or comes before
+    /// a query or path pair for the same host, instead of just warning
+    #[clap(long)]
+    pub(crate) strict: bool,
+    /// Print the redirect table and exit without binding
+    #[clap(long)]
+    pub(crate) check: bool,
+    /// Host/port to bind to, may be given multiple times.
+    /// Defaults to 0.0.0.0 on $PORT, or 0.0.0.0:3000 if PORT is unset
+    #[clap(long)]
+    pub(crate) bind: Vec<SocketAddr>,
+    /// Maximum number of pending connections waiting to be accepted
+    #[clap(long, default_value = "128")]
+    pub(crate) backlog: i32,
+    /// Set SO_REUSEPORT so several processes can share the same address, e.g.
+    /// while restarting
+    #[clap(long)]
+    pub(crate) reuse_port: bool,
+    /// Unix domain socket to listen on instead of a TCP address
+    #[clap(long, conflicts_with = "bind")]
+    pub(crate) unix: Option<PathBuf>,
+    /// Already-bound TCP listener file descriptor to serve on, e.g. 3 for systemd
+    /// socket activation. Detected from LISTEN_FDS when no other listener is given
+    #[clap(long, conflicts_with_all = &["bind", "unix"])]
+    pub(crate) fd: Option<i32>,
+    /// PEM certificate chain to serve HTTPS with on TCP listeners, instead of
+    /// plain HTTP
+    #[clap(long, requires = "tls-key")]
+    pub(crate) tls_cert: Option<PathBuf>,
+    /// PEM private key for --tls-cert
+    #[clap(long, requires = "tls-cert")]
+    pub(crate) tls_key: Option<PathBuf>,
+    /// Domain to get a certificate for from Let's Encrypt, answering its http-01
+    /// challenges on the usual listeners and serving HTTPS on --acme-bind. The
+    /// certificate is renewed in the background. May be given multiple times
+    #[cfg(feature = "acme")]
+    #[clap(long, requires = "acme-email", conflicts_with = "tls-cert")]
+    pub(crate) acme_domain: Vec<String>,
+    /// Contact email for the Let's Encrypt account
+    #[cfg(feature = "acme")]
+    #[clap(long, requires = "acme-domain")]
+    pub(crate) acme_email: Option<String>,
+    /// Directory to keep the ACME account key and certificates in
+    #[cfg(feature = "acme")]
+    #[clap(long, default_value = ".acme")]
+    pub(crate) acme_dir: PathBuf,
+    /// Host/port to serve HTTPS on with the ACME certificate
+    #[cfg(feature = "acme")]
+    #[clap(long, default_value = "0.0.0.0:443")]
+    pub(crate) acme_bind: SocketAddr,
+    /// Use the Let's Encrypt staging environment, whose certificates aren't
+    /// trusted but which has much higher rate limits, for trying out a setup
+    #[cfg(feature = "acme")]
+    #[clap(long, requires = "acme-domain")]
+    pub(crate) acme_staging: bool,
+    /// Host/port to serve Prometheus metrics on at /metrics, and a plain text
+    /// summary of hits per pair at /debug/stats
+    #[clap(long)]
+    pub(crate) metrics_bind: Option<SocketAddr>,
+}
+
+/// Settings loaded from a --config file
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Config {
+    #[serde(default)]
+    pub(crate) bind: Vec<String>,
+    #[serde(default)]
+    pub(crate) insecure: bool,
+    pub(crate) fallback: Option<String>,
+    pub(crate) status: Option<u16>,
+    /// Source<->dest pairs in the same format as the command line
+    #[serde(default)]
+    pub(crate) pairs: Vec<String>,
+    /// Host rewrites in the same format as --rewrite
+    #[serde(default)]
+    pub(crate) rewrite: Vec<String>,
+}
+
+impl Config {
+    pub(crate) fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Unable to read config file {}", path.display()))?;
+        let mut config: Config = toml::from_str(&contents)
+            .with_context(|| format!("Invalid config file {}", path.display()))?;
+        config
+            .expand_env()
+            .with_context(|| format!("Invalid config file {}", path.display()))?;
+        Ok(config)
+    }
+
+    /// Expand ${VAR} references in the bind addresses, fallback, and pairs.
+    ///
+    /// This happens before the pairs are parsed, so a variable can hold any part
+    /// of a pair. Rewrites are left alone, since ${1} is a capture group there.
+    pub(crate) fn expand_env(&mut self) -> anyhow::Result<()> {
+        for bind in &mut self.bind {
+            *bind = expand_env(bind)?;
+        }
+        if let Some(fallback) = &mut self.fallback {
+            *fallback = expand_env(fallback)?;
+        }
+        for pair in &mut self.pairs {
+            *pair = expand_env(pair)?;
+        }
+        Ok(())
+    }
+
+    /// Check everything in the config file on its own, without the command line,
+    /// returning a short summary
+    pub(crate) fn validate(self, on_duplicate: OnDuplicate) -> anyhow::Result<String> {
+        for bind in &self.bind {
+            bind.parse::<SocketAddr>()
+                .with_context(|| format!("Invalid bind address {}", bind))?;
+        }
+        if let Some(status) = self.status {
+            RedirectStatus::new(status)?;
+        }
+        let pairs = self
+            .pairs
+            .iter()
+            .map(|pair| pair.parse())
+            .collect::<anyhow::Result<Vec<DomainPair>>>()?;
+        for rewrite in &self.rewrite {
+            rewrite.parse::<Rewrite>()?;
+        }
+        let rules = Rules::new(pairs, self.fallback, on_duplicate)?;
+        Ok(format!(
+            "{} pairs, {} rewrites, {}",
+            rules.pair_count(),
+            self.rewrite.len(),
+            if rules.fallback.is_some() {
+                "with a fallback"
+            } else {
+                "no fallback"
+            }
+        ))
+    }
+}
+
+impl Opt {
+    /// Fill in anything not given on the command line from the config file
+    pub(crate) fn merge_config(&mut self, config: Config) -> anyhow::Result<()> {
+        if self.bind.is_empty() {
+            for bind in config.bind {
+                self.bind.push(
+                    bind.parse()
+                        .with_context(|| format!("Invalid bind address {}", bind))?,
+                );
+            }
+        }
+        self.insecure |= config.insecure;
+        if self.fallback.is_none() {
+            self.fallback = config.fallback;
+        }
+        if self.status.is_none() && !self.temporary {
+            if let Some(status) = config.status {
+                self.status = Some(RedirectStatus::new(status)?);
+            }
+        }
+        for pair in config.pairs {
+            self.pairs.push(pair.parse()?);
+        }
+        for rewrite in config.rewrite {
+            self.rewrite.push(rewrite.parse()?);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum TrailingSlash {
+    Add,
+    Strip,
+    Keep,
+}
+
+impl FromStr for TrailingSlash {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "add" => Ok(TrailingSlash::Add),
+            "strip" => Ok(TrailingSlash::Strip),
+            "keep" => Ok(TrailingSlash::Keep),
+            _ => Err(anyhow::anyhow!(
+                "Invalid trailing slash mode {}, must be add, strip, or keep",
+                s
+            )),
+        }
+    }
+}
+
+impl TrailingSlash {
+    /// Rewrite the path portion of a path and query, leaving the root path alone
+    pub(crate) fn apply(self, path: &mut Cow<str>) {
+        let end = path.find('?').unwrap_or_else(|| path.len());
+        match self {
+            TrailingSlash::Add => {
+                if end > 0 && !path[..end].ends_with('/') {
+                    path.to_mut().insert(end, '/');
+                }
+            }
+            TrailingSlash::Strip => {
+                let trimmed = path[..end].trim_end_matches('/').len().max(1);
+                if trimmed < end {
+                    path.to_mut().replace_range(trimmed..end, "");
+                }
+            }
+            TrailingSlash::Keep => (),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum WwwMode {
+    Strip,
+    Add,
+}
+
+pub(crate) struct AllowedMethods {
+    pub(crate) methods: Vec<Method>,
+    /// Precomputed value for the Allow header
+    pub(crate) allow: HeaderValue,
+}
+
+impl FromStr for AllowedMethods {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let methods = s
+            .split(',')
+            .map(|method| {
+                let method = method.trim().to_ascii_uppercase();
+                Method::from_str(&method).with_context(|| format!("Invalid method {}", method))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let allow = methods
+            .iter()
+            .map(Method::as_str)
+            .collect::<Vec<_>>()
+            .join(", ");
+        Ok(AllowedMethods {
+            methods,
+            allow: HeaderValue::from_str(&allow)?,
+        })
+    }
+}
+
+/// Maintenance mode, which bounces every request to a status page
+pub(crate) struct Maintenance {
+    pub(crate) location: HeaderValue,
+    /// Seconds for the Retry-After header
+    pub(crate) retry_after: u64,
+    /// Switches maintenance mode on while it exists, if given
+    pub(crate) file: Option<PathBuf>,
+    /// Whether we're currently in maintenance mode
+    pub(crate) enabled: AtomicBool,
+}
+
+/// Headers for answering CORS preflight requests
+pub(crate) struct Cors {
+    pub(crate) origin: HeaderValue,
+    pub(crate) headers: Option<HeaderValue>,
+}
+
+pub(crate) struct RedirectStatus(pub(crate) StatusCode);
+
+impl FromStr for RedirectStatus {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse() {
+            Ok(code) => RedirectStatus::new(code),
+            Err(_) => Err(anyhow::anyhow!(
+                "Invalid redirect status {}, must be one of 301, 302, 303, 307, or 308",
+                s
+            )),
+        }
+    }
+}
+
+impl RedirectStatus {
+    pub(crate) fn new(code: u16) -> anyhow::Result<Self> {
+        match StatusCode::from_u16(code) {
+            Ok(code) if REDIRECT_STATUSES.contains(&code) => Ok(RedirectStatus(code)),
+            _ => Err(anyhow::anyhow!(
+                "Invalid redirect status {}, must be one of 301, 302, 303, 307, or 308",
+                code
+            )),
+        }
+    }
+}
+
+pub(crate) struct CustomHeader(pub(crate) HeaderName, pub(crate) HeaderValue);
+
+impl FromStr for CustomHeader {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, value) = s
+            .split_once(':')
+            .with_context(|| format!("Invalid header {}, expected Name: Value", s))?;
+        let name = HeaderName::from_str(name.trim())
+            .with_context(|| format!("Invalid header name {}", name))?;
+        let value = HeaderValue::from_str(value.trim())
+            .with_context(|| format!("Invalid header value {}", value))?;
+        Ok(CustomHeader(name, value))
+    }
+}
+
+pub(crate) struct ClientErrorStatus(pub(crate) StatusCode);
+
+impl FromStr for ClientErrorStatus {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse::<u16>().ok().map(StatusCode::from_u16) {
+            Some(Ok(code)) if code.is_client_error() => Ok(ClientErrorStatus(code)),
+            _ => Err(anyhow::anyhow!(
+                "Invalid status {}, must be a 4xx client error such as 400, 404, or 421",
+                s
+            )),
+        }
+    }
+}
+
+pub(crate) const REDIRECT_STATUSES: [StatusCode; 5] = [
+    StatusCode::MOVED_PERMANENTLY,
+    StatusCode::FOUND,
+    StatusCode::SEE_OTHER,
+    StatusCode::TEMPORARY_REDIRECT,
+    StatusCode::PERMANENT_REDIRECT,
+];
+
+/// Replace each ${VAR} with the value of that environment variable, failing if
+/// it isn't set
+pub(crate) fn expand_env(s: &str) -> anyhow::Result<String> {
+    let mut out = String::new();
+    let mut rest = s;
+    while let Some(start) = rest.find("${") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after
+            .find('}')
+            .with_context(|| format!("Unterminated ${{ in {}", s))?;
+        let name = &after[..end];
+        let value = std::env::var(name)
+            .with_context(|| format!("Environment variable {} is not set", name))?;
+        out.push_str(&value);
+        rest = &after[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
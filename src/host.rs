@@ -0,0 +1,71 @@
+//! Parsing and normalizing Host header values
+
+/// Check that a Host header value only contains characters valid in a
+/// hostname, optionally with a port or bracketed IPv6 literal.
+///
+/// Non-ASCII bytes are allowed through so that internationalized names can
+/// still be matched.
+pub(crate) fn validate_host(host: &[u8]) -> bool {
+    host.iter().all(|&b| {
+        b.is_ascii_alphanumeric()
+            || matches!(b, b'-' | b'.' | b'_' | b':' | b'[' | b']')
+            || b >= 0x80
+    })
+}
+
+/// The port from a Host header value, if present
+pub(crate) fn port(host: &[u8]) -> Option<&str> {
+    let port = &host[strip_port(host).len()..];
+    std::str::from_utf8(port.strip_prefix(b":")?).ok()
+}
+
+pub(crate) fn is_default_port(scheme: &str, port: &str) -> bool {
+    matches!((scheme, port), ("http", "80") | ("https", "443"))
+}
+
+/// Add a port to the host portion of a destination, unless it already has one
+pub(crate) fn add_port(dest: &str, port: &str) -> String {
+    let (host, path) = dest.split_at(dest.find('/').unwrap_or(dest.len()));
+    if strip_port(host.as_bytes()).len() == host.len() {
+        format!("{}:{}{}", host, port, path)
+    } else {
+        dest.to_owned()
+    }
+}
+
+/// Normalize a host for lookup in the domain map.
+///
+/// DNS names are case-insensitive, so we lowercase ASCII letters only.
+/// Unicode names are converted to punycode so that both forms match the same
+/// pair, returning None if that conversion fails. Any other non-UTF8 bytes
+/// are left untouched. Since pairs are always UTF-8, such a host never equals a
+/// source, and can only be matched by a wildcard or domain fallback for a
+/// parent domain, the catch-all, or the fallback. A single trailing dot, as in
+/// the fully-qualified example.com., is removed.
+pub(crate) fn normalize_host(host: &[u8]) -> Option<Vec<u8>> {
+    let host = host.strip_suffix(b".").unwrap_or(host);
+    if host.is_ascii() {
+        return Some(host.to_ascii_lowercase());
+    }
+    match std::str::from_utf8(host) {
+        Ok(host) => idna::domain_to_ascii(host).ok().map(String::into_bytes),
+        Err(_) => Some(host.to_ascii_lowercase()),
+    }
+}
+
+/// Remove an optional trailing :port from a Host header value.
+///
+/// Bracketed IPv6 literals like [::1]:8080 keep their brackets.
+pub(crate) fn strip_port(host: &[u8]) -> &[u8] {
+    if host.starts_with(b"[") {
+        return match host.iter().position(|&b| b == b']') {
+            Some(end) => &host[..=end],
+            None => host,
+        };
+    }
+    let mut colons = host.iter().enumerate().filter(|&(_, &b)| b == b':');
+    match (colons.next(), colons.next()) {
+        (Some((idx, _)), None) => &host[..idx],
+        _ => host,
+    }
+}
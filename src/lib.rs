@@ -0,0 +1,203 @@
+//! Simple no-config utility for redirecting between domains.
+//!
+//! The binary just calls [`run`], while [`App`] can be used to try out a
+//! configuration in-process without binding a socket.
+
+#[cfg(feature = "acme")]
+mod acme;
+mod app;
+mod config;
+mod host;
+mod log;
+mod metrics;
+mod pairs;
+mod rate_limit;
+mod response;
+mod rules;
+mod server;
+mod tls;
+
+pub use crate::{app::App, pairs::DomainPair};
+
+use std::{
+    net::SocketAddr,
+    sync::{atomic::Ordering, Arc},
+};
+
+use anyhow::Context;
+use clap::StructOpt;
+
+use crate::{
+    config::{Config, Opt},
+    log::StartupLog,
+    pairs::{fetch_pairs_retrying, load_rules, read_pairs_stdin},
+    rate_limit::RATE_LIMIT_WINDOW,
+    server::{
+        bind_tcp, default_bind, serve, serve_acme, serve_fd, serve_tcp, serve_unix,
+        spawn_maintenance_watcher, spawn_shutdown_listener, systemd_listen_fd, wait_for_shutdown,
+    },
+};
+
+/// Run the redirector with the command line arguments, until shutdown
+pub async fn run() -> anyhow::Result<()> {
+    let mut opt = Opt::parse();
+    if let Some(path) = &opt.validate_config {
+        let summary = Config::load(path)?
+            .validate(opt.on_duplicate)
+            .with_context(|| format!("Invalid config file {}", path.display()))?;
+        println!("{} is valid: {}", path.display(), summary);
+        return Ok(());
+    }
+    if let Some(config) = opt.config.clone() {
+        opt.merge_config(Config::load(&config)?)?;
+    }
+    // Stdin can only be read once, so these are kept with the command line
+    // pairs when reloading the pairs file
+    if opt.pairs_stdin {
+        opt.pairs.extend(read_pairs_stdin()?);
+    }
+    if let Some(url) = &opt.pairs_url {
+        let pairs = fetch_pairs_retrying(url, opt.startup_retries)
+            .await
+            .with_context(|| format!("Unable to load pairs from {}", url))?;
+        opt.pairs.extend(pairs);
+    }
+
+    let rules = load_rules(
+        &opt.pairs,
+        opt.pairs_file.as_deref(),
+        opt.fallback.clone(),
+        opt.on_duplicate,
+    )?;
+    let overlaps = rules.overlaps();
+    for overlap in &overlaps {
+        eprintln!("Warning: overlapping pairs, {}", overlap);
+    }
+    if opt.strict && !overlaps.is_empty() {
+        anyhow::bail!("Refusing to start with overlapping pairs under --strict");
+    }
+    if opt.check {
+        rules.print(if opt.insecure { "http" } else { "https" });
+        for rewrite in &opt.rewrite {
+            println!("{} -> {}", rewrite.regex, rewrite.template);
+        }
+        return Ok(());
+    }
+    #[cfg(unix)]
+    let (pairs, pairs_file, fallback, on_duplicate) = (
+        opt.pairs.clone(),
+        opt.pairs_file.clone(),
+        opt.fallback.clone(),
+        opt.on_duplicate,
+    );
+    let unix = opt.unix.clone();
+    let fd = opt.fd;
+    let metrics_bind = opt.metrics_bind;
+    let bind = opt.bind.clone();
+    let app = Arc::new(App::from_opt(opt, rules)?);
+
+    #[cfg(unix)]
+    {
+        use crate::server::{spawn_reload_listener, spawn_verbose_listener};
+        if let Some(pairs_file) = pairs_file {
+            spawn_reload_listener(app.clone(), pairs, pairs_file, fallback, on_duplicate)?;
+        }
+        spawn_verbose_listener(app.clone())?;
+    }
+
+    if app
+        .maintenance
+        .as_ref()
+        .map_or(false, |maintenance| maintenance.file.is_some())
+    {
+        spawn_maintenance_watcher(app.clone());
+    }
+
+    if app.rate_limiter.is_some() {
+        let app = app.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(RATE_LIMIT_WINDOW);
+            loop {
+                interval.tick().await;
+                if let Some(rate_limiter) = &app.rate_limiter {
+                    rate_limiter.sweep();
+                }
+            }
+        });
+    }
+
+    let fd = match fd {
+        None if unix.is_none() && bind.is_empty() => systemd_listen_fd(),
+        fd => fd,
+    };
+    let binds = if unix.is_none() && fd.is_none() && bind.is_empty() {
+        vec![default_bind()?]
+    } else {
+        bind
+    };
+    let listen = match (&unix, fd) {
+        (Some(path), _) => vec![format!("unix:{}", path.display())],
+        (None, Some(fd)) => vec![format!("fd:{}", fd)],
+        (None, None) => binds.iter().map(SocketAddr::to_string).collect(),
+    };
+    #[cfg(feature = "acme")]
+    let listen = match &app.acme {
+        Some(acme) => {
+            crate::acme::spawn_acme(app.clone());
+            [listen, vec![format!("https:{}", acme.bind)]].concat()
+        }
+        None => listen,
+    };
+    let scheme = if app.insecure { "http" } else { "https" };
+    let rules = app.rules.load();
+    let startup = app.log_format.format_startup(&StartupLog {
+        listen: &listen,
+        scheme,
+        status: app.status,
+        fallback: rules
+            .fallback
+            .as_ref()
+            .map(|fallback| format!("{}://{}", fallback.scheme.unwrap_or(scheme), fallback.host)),
+        pairs: rules.pair_count(),
+    });
+    app.log_sink.write_line(&startup);
+    drop(rules);
+
+    let shutdown = spawn_shutdown_listener();
+    let server = async {
+        match (&unix, fd) {
+            (Some(path), _) => serve_unix(path, app.clone(), shutdown.clone()).await,
+            (None, Some(fd)) => serve_fd(fd, app.clone(), shutdown.clone()).await,
+            (None, None) => serve_tcp(&binds, app.clone(), shutdown.clone()).await,
+        }
+    };
+    let metrics_server = async {
+        match &metrics_bind {
+            None => Ok(()),
+            Some(metrics_bind) => {
+                let incoming = bind_tcp(metrics_bind, &app)?;
+                serve(incoming, app.clone(), App::handle_metrics, shutdown.clone())
+                    .await
+                    .context("Metrics server exited unexpectedly")
+            }
+        }
+    };
+    let acme_server = serve_acme(app.clone(), shutdown.clone());
+    let shutdown_timeout = async {
+        wait_for_shutdown(shutdown.clone()).await;
+        tokio::time::sleep(app.shutdown_timeout).await;
+    };
+    let res = tokio::select! {
+        res = async { tokio::try_join!(server, metrics_server, acme_server) } => res.map(|_| ()),
+        () = shutdown_timeout => {
+            eprintln!(
+                "Timed out draining requests, abandoning {} connections",
+                app.connections.load(Ordering::Relaxed)
+            );
+            Ok(())
+        }
+    };
+    app.log_sink.flush();
+    res?;
+    Ok(())
+}
@@ -0,0 +1,283 @@
+//! Access and startup logs, in each --log-format, and where they are written
+
+use std::{
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Mutex, PoisonError},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use hyper::{header::HeaderValue, Method, StatusCode, Uri, Version};
+
+/// Only log server errors and requests without a host
+pub(crate) const QUIET: u8 = 0;
+/// Log failed requests
+pub(crate) const NORMAL: u8 = 1;
+/// Log every request
+pub(crate) const VERBOSE: u8 = 2;
+
+#[derive(Clone, Copy)]
+pub(crate) enum LogFormat {
+    Text,
+    Json,
+    /// Apache combined log format
+    Clf,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            "clf" => Ok(LogFormat::Clf),
+            _ => Err(anyhow::anyhow!(
+                "Invalid log format {}, must be text, json, or clf",
+                s
+            )),
+        }
+    }
+}
+
+/// Information about a single request, filled in while handling it
+pub(crate) struct RequestLog<'a> {
+    pub(crate) request_id: HeaderValue,
+    /// When the request was received
+    pub(crate) received: SystemTime,
+    pub(crate) client: Option<IpAddr>,
+    pub(crate) method: &'a Method,
+    pub(crate) host: Option<&'a HeaderValue>,
+    pub(crate) uri: &'a Uri,
+    pub(crate) version: Version,
+    pub(crate) referer: Option<&'a HeaderValue>,
+    pub(crate) user_agent: Option<&'a HeaderValue>,
+    pub(crate) location: Option<HeaderValue>,
+    pub(crate) status: StatusCode,
+    /// Size of the response body, if known
+    pub(crate) size: Option<u64>,
+    pub(crate) fallback: bool,
+    /// Time spent handling the request
+    pub(crate) elapsed: Duration,
+}
+
+/// The effective configuration, logged once before serving
+pub(crate) struct StartupLog<'a> {
+    pub(crate) listen: &'a [String],
+    pub(crate) scheme: &'static str,
+    pub(crate) status: StatusCode,
+    pub(crate) fallback: Option<String>,
+    pub(crate) pairs: usize,
+}
+
+impl LogFormat {
+    pub(crate) fn format_startup(self, log: &StartupLog) -> String {
+        match self {
+            // Access log tools skip lines they can't parse
+            LogFormat::Text | LogFormat::Clf => format!(
+                "Listening on {}, redirecting {} pairs to {} with status {}, fallback {}",
+                log.listen.join(", "),
+                log.pairs,
+                log.scheme,
+                log.status.as_u16(),
+                log.fallback.as_deref().unwrap_or("none")
+            ),
+            LogFormat::Json => serde_json::json!({
+                "listen": log.listen,
+                "scheme": log.scheme,
+                "status": log.status.as_u16(),
+                "fallback": log.fallback,
+                "pairs": log.pairs,
+            })
+            .to_string(),
+        }
+    }
+
+    pub(crate) fn format_request(self, log: &RequestLog) -> String {
+        let request_id = String::from_utf8_lossy(log.request_id.as_bytes());
+        match self {
+            LogFormat::Text => {
+                let elapsed = log.elapsed.as_micros();
+                let client = match log.client {
+                    Some(client) => format!(" from {}", client),
+                    None => String::new(),
+                };
+                match log.host {
+                    None => format!(
+                        "[{}] Received request without hostname{} ({}us)",
+                        request_id, client, elapsed
+                    ),
+                    Some(host) => match host.to_str() {
+                        Ok(host) => format!(
+                            "[{}] Received request for http://{}{}{} ({}us)",
+                            request_id, host, log.uri, client, elapsed
+                        ),
+                        Err(_) => format!(
+                            "[{}] Received request for non-UTF8 host {:?} with URI {}{} ({}us)",
+                            request_id, host, log.uri, client, elapsed
+                        ),
+                    },
+                }
+            }
+            LogFormat::Json => serde_json::json!({
+                "request_id": request_id,
+                "client": log.client.map(|client| client.to_string()),
+                "host": log.host.map(|host| String::from_utf8_lossy(host.as_bytes())),
+                "uri": log.uri.to_string(),
+                "location": log
+                    .location
+                    .as_ref()
+                    .map(|location| String::from_utf8_lossy(location.as_bytes())),
+                "status": log.status.as_u16(),
+                "fallback": log.fallback,
+                "elapsed_us": log.elapsed.as_micros() as u64,
+            })
+            .to_string(),
+            LogFormat::Clf => {
+                let quoted = |value: Option<&HeaderValue>| match value {
+                    Some(value) => String::from_utf8_lossy(value.as_bytes()).replace('"', "\\\""),
+                    None => "-".to_owned(),
+                };
+                format!(
+                    "{} - - [{}] \"{} {} {:?}\" {} {} \"{}\" \"{}\"",
+                    log.client
+                        .map_or_else(|| "-".to_owned(), |client| client.to_string()),
+                    clf_time(log.received),
+                    log.method,
+                    log.uri,
+                    log.version,
+                    log.status.as_u16(),
+                    log.size
+                        .filter(|&size| size > 0)
+                        .map_or_else(|| "-".to_owned(), |size| size.to_string()),
+                    quoted(log.referer),
+                    quoted(log.user_agent),
+                )
+            }
+        }
+    }
+}
+
+pub(crate) const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Format a time in UTC like 10/Oct/2000:13:55:36 +0000, as in Apache logs
+pub(crate) fn clf_time(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    let days = (secs / 86400) as i64;
+    let secs = secs % 86400;
+    // Convert days since the epoch to a civil date, from
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+/// Where access logs are written: stderr, or a file rotated by size
+pub(crate) enum LogSink {
+    Stderr,
+    File(Mutex<LogFile>),
+}
+
+pub(crate) struct LogFile {
+    pub(crate) path: PathBuf,
+    /// Rotate to path.1 once the file would grow past this many bytes
+    pub(crate) max_size: Option<u64>,
+    pub(crate) writer: std::io::LineWriter<std::fs::File>,
+    pub(crate) size: u64,
+}
+
+impl LogSink {
+    pub(crate) fn open(path: &Path, max_size: Option<u64>) -> anyhow::Result<Self> {
+        let file = LogFile::open(path)?;
+        let size = file
+            .metadata()
+            .with_context(|| format!("Unable to read log file {}", path.display()))?
+            .len();
+        Ok(LogSink::File(Mutex::new(LogFile {
+            path: path.to_owned(),
+            max_size,
+            writer: std::io::LineWriter::new(file),
+            size,
+        })))
+    }
+
+    pub(crate) fn write_line(&self, line: &str) {
+        match self {
+            LogSink::Stderr => eprintln!("{}", line),
+            LogSink::File(file) => {
+                let mut file = file.lock().unwrap_or_else(PoisonError::into_inner);
+                if let Err(e) = file.write_line(line) {
+                    eprintln!(
+                        "Unable to write to log file {}: {:?}",
+                        file.path.display(),
+                        e
+                    );
+                }
+            }
+        }
+    }
+
+    pub(crate) fn flush(&self) {
+        if let LogSink::File(file) = self {
+            let mut file = file.lock().unwrap_or_else(PoisonError::into_inner);
+            if let Err(e) = std::io::Write::flush(&mut file.writer) {
+                eprintln!("Unable to flush log file {}: {:?}", file.path.display(), e);
+            }
+        }
+    }
+}
+
+impl LogFile {
+    pub(crate) fn open(path: &Path) -> anyhow::Result<std::fs::File> {
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Unable to open log file {}", path.display()))
+    }
+
+    pub(crate) fn write_line(&mut self, line: &str) -> anyhow::Result<()> {
+        let len = line.len() as u64 + 1;
+        if let Some(max_size) = self.max_size {
+            if self.size > 0 && self.size + len > max_size {
+                self.rotate()?;
+            }
+        }
+        std::io::Write::write_all(&mut self.writer, line.as_bytes())?;
+        std::io::Write::write_all(&mut self.writer, b"\n")?;
+        self.size += len;
+        Ok(())
+    }
+
+    /// Move the current file to path.1, replacing any previous one, and start afresh
+    pub(crate) fn rotate(&mut self) -> anyhow::Result<()> {
+        std::io::Write::flush(&mut self.writer)?;
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(".1");
+        std::fs::rename(&self.path, &rotated)?;
+        self.writer = std::io::LineWriter::new(LogFile::open(&self.path)?);
+        self.size = 0;
+        Ok(())
+    }
+}
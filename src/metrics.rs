@@ -0,0 +1,40 @@
+//! Request counters for the metrics server
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use hyper::StatusCode;
+
+/// Request counters, updated on every request and served by the metrics server
+#[derive(Default)]
+pub(crate) struct Metrics {
+    pub(crate) redirects: AtomicU64,
+    pub(crate) fallbacks: AtomicU64,
+    pub(crate) client_errors: AtomicU64,
+    pub(crate) server_errors: AtomicU64,
+}
+
+impl Metrics {
+    /// Count a response, whether or not it came from routing the request
+    pub(crate) fn record(&self, status: StatusCode) {
+        if status.is_redirection() {
+            self.redirects.fetch_add(1, Ordering::Relaxed);
+        } else if status.is_client_error() {
+            self.client_errors.fetch_add(1, Ordering::Relaxed);
+        } else if status.is_server_error() {
+            self.server_errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Count a request sent to a fallback destination
+    pub(crate) fn record_fallback(&self) {
+        self.fallbacks.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Escape a Prometheus label value
+pub(crate) fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,409 @@
+//! Source=dest pairs, and loading them from the command line, files, stdin, or a URL
+
+use std::{borrow::Cow, path::Path, str::FromStr, time::Duration};
+
+use anyhow::Context;
+use hyper::{header::HeaderValue, StatusCode, Uri};
+use regex::Regex;
+
+use crate::{
+    config::RedirectStatus,
+    host::normalize_host,
+    rules::{OnDuplicate, QueryMatch, Rules},
+};
+
+/// A source=dest pair, parsed with [`FromStr`] from the same syntax as the
+/// command line
+#[derive(Clone)]
+pub struct DomainPair {
+    /// Sources sharing this destination, more than one if they were given
+    /// comma-separated
+    pub(crate) sources: Vec<PairSource>,
+    /// The destination, or the first of a weighted split
+    pub(crate) dest: Destination,
+    pub(crate) split: Option<Split>,
+    /// Redirect status for this pair, overriding --status, or a status other
+    /// than 3xx to respond with instead of redirecting
+    pub(crate) status: Option<StatusCode>,
+}
+
+#[derive(Clone)]
+pub(crate) struct PairSource {
+    pub(crate) host: String,
+    /// Whether the source was given as *.source
+    pub(crate) wildcard: bool,
+    /// Path prefix to match, if the source included a path
+    pub(crate) path: Option<String>,
+    /// Query parameter to match, if the source included a query
+    pub(crate) query: Option<QueryMatch>,
+}
+
+#[derive(Clone)]
+pub(crate) struct Destination {
+    /// Scheme given explicitly in the pair, overriding --insecure
+    pub(crate) scheme: Option<&'static str>,
+    pub(crate) host: String,
+}
+
+impl Destination {
+    pub(crate) fn parse(s: &str) -> Self {
+        for scheme in ["http", "https"] {
+            if let Some(host) = s
+                .strip_prefix(scheme)
+                .and_then(|rest| rest.strip_prefix("://"))
+            {
+                return Destination {
+                    scheme: Some(scheme),
+                    host: bracket_ipv6(host),
+                };
+            }
+        }
+        Destination {
+            scheme: None,
+            host: bracket_ipv6(s),
+        }
+    }
+
+    /// Make sure a redirect to this destination can be sent as a Location header,
+    /// so that a bad pair fails at startup instead of on every request
+    pub(crate) fn validate(&self) -> anyhow::Result<()> {
+        let location = format!("{}://{}", self.scheme.unwrap_or("https"), self.host);
+        HeaderValue::from_str(&location)
+            .map(|_| ())
+            .with_context(|| format!("Destination {} is not a valid header value", location))
+    }
+}
+
+/// Wrap a bare IPv6 literal destination such as ::1 in brackets, so that it
+/// can be followed by a path in the Location URL
+pub(crate) fn bracket_ipv6(dest: &str) -> String {
+    let (authority, rest) = match dest.find('/') {
+        Some(idx) => dest.split_at(idx),
+        None => (dest, ""),
+    };
+    if authority.parse::<std::net::Ipv6Addr>().is_ok() {
+        format!("[{}]{}", authority, rest)
+    } else {
+        dest.to_owned()
+    }
+}
+
+/// A fallback for any subdomain of a parent domain
+pub(crate) struct DomainFallback {
+    /// Normalized parent domain, with a leading dot
+    pub(crate) suffix: Vec<u8>,
+    pub(crate) dest: Destination,
+}
+
+impl FromStr for DomainFallback {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (domain, dest) = s
+            .split_once('=')
+            .with_context(|| format!("Invalid domain fallback {}, expected domain=dest", s))?;
+        let domain = domain.trim_start_matches("*.").trim_start_matches('.');
+        let mut suffix = vec![b'.'];
+        suffix.extend(
+            normalize_host(domain.as_bytes())
+                .with_context(|| format!("Invalid domain name {}", domain))?,
+        );
+        let dest = Destination::parse(dest);
+        if dest.host.is_empty() {
+            anyhow::bail!("Empty destination for domain fallback {}", s);
+        }
+        dest.validate()?;
+        Ok(DomainFallback { suffix, dest })
+    }
+}
+
+impl DomainFallback {
+    pub(crate) fn matches(&self, host: &[u8]) -> bool {
+        host.ends_with(&self.suffix)
+    }
+}
+
+/// Destinations chosen between at random for each request, for gradual migrations
+#[derive(Clone)]
+pub(crate) struct Split {
+    /// Each destination with the running total of the weights up to and including it
+    pub(crate) choices: Vec<(u32, Destination)>,
+}
+
+impl Split {
+    /// Parse a dest like 90%new.com,10%old.com, with percentages adding up to 100
+    pub(crate) fn parse(s: &str) -> Option<Self> {
+        let mut total = 0;
+        let mut choices = vec![];
+        for piece in s.split(',') {
+            let (weight, dest) = piece.split_once('%')?;
+            let weight = weight
+                .trim()
+                .parse::<u32>()
+                .ok()
+                .filter(|&weight| weight <= 100 - total)?;
+            let dest = dest.trim();
+            if dest.is_empty() {
+                return None;
+            }
+            total += weight;
+            choices.push((total, Destination::parse(dest)));
+        }
+        if total == 100 {
+            Some(Split { choices })
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn choose(&self) -> &Destination {
+        let roll = rand::random::<u32>() % 100;
+        self.choices
+            .iter()
+            .find(|(total, _)| roll < *total)
+            .map(|(_, dest)| dest)
+            .expect("split weights add up to 100")
+    }
+}
+
+pub(crate) struct Rewrite {
+    pub(crate) regex: Regex,
+    pub(crate) template: String,
+}
+
+impl FromStr for Rewrite {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (regex, template) = s
+            .rsplit_once('=')
+            .with_context(|| format!("Invalid rewrite {}, expected regex=template", s))?;
+        let regex =
+            Regex::new(regex).with_context(|| format!("Invalid rewrite regex {}", regex))?;
+        Ok(Rewrite {
+            regex,
+            template: template.to_owned(),
+        })
+    }
+}
+
+impl Rewrite {
+    /// Apply the rewrite to a host, substituting $1, $2, etc. into the template
+    pub(crate) fn apply(&self, host: &str) -> Option<Destination> {
+        let captures = self.regex.captures(host)?;
+        let mut dest = String::new();
+        captures.expand(&self.template, &mut dest);
+        Some(Destination::parse(&dest))
+    }
+}
+
+impl FromStr for DomainPair {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match DomainPair::parse_option(s) {
+            None => Err(anyhow::anyhow!("Invalid domain pair: {}", s)),
+            Some(pair) => Ok(pair),
+        }
+    }
+}
+
+impl DomainPair {
+    pub(crate) fn parse_option(s: &str) -> Option<Self> {
+        // A prefix like 301|example.com=new.com overrides --status for this pair
+        let (status, s) = match s.split_once('|') {
+            Some((status, rest)) => (
+                Some(RedirectStatus::new(status.trim().parse().ok()?).ok()?.0),
+                rest,
+            ),
+            None => (None, s),
+        };
+        let pieces: Vec<&str> = s.split('=').collect();
+        let (sources, dest) = match pieces.as_slice() {
+            [sources, dest] => (Cow::Borrowed(*sources), *dest),
+            // The = in a query parameter like example.com?legacy=1=old.com
+            [sources, value, dest] if sources.contains('?') => {
+                (Cow::Owned(format!("{}={}", sources, value)), *dest)
+            }
+            _ => return None,
+        };
+        let sources = sources.split(',').map(PairSource::parse).collect();
+        // A dest of 410 or 204 isn't a host but a response to send instead of
+        // redirecting, for URLs that are gone for good or only need acknowledging
+        let respond = match dest {
+            "410" => Some(StatusCode::GONE),
+            "204" => Some(StatusCode::NO_CONTENT),
+            _ => None,
+        };
+        if let Some(respond) = respond {
+            if status.is_some() {
+                return None;
+            }
+            return Some(DomainPair {
+                sources,
+                dest: Destination::parse(""),
+                split: None,
+                status: Some(respond),
+            });
+        }
+        // A dest like 90%new.com,10%old.com is a weighted split
+        let is_split = dest.split_once('%').map_or(false, |(weight, _)| {
+            !weight.is_empty() && weight.bytes().all(|b| b.is_ascii_digit())
+        });
+        let (dest, split) = if is_split {
+            let split = Split::parse(dest)?;
+            (split.choices[0].1.clone(), Some(split))
+        } else {
+            (Destination::parse(dest), None)
+        };
+        Some(DomainPair {
+            sources,
+            dest,
+            split,
+            status,
+        })
+    }
+}
+
+impl PairSource {
+    pub(crate) fn parse(source: &str) -> Self {
+        let (source, query) = match source.split_once('?') {
+            Some((source, query)) => (source, Some(QueryMatch::parse(query))),
+            None => (source, None),
+        };
+        let (source, path) = match source.find('/') {
+            Some(idx) => {
+                let prefix = source[idx..].trim_end_matches('*').trim_end_matches('/');
+                (&source[..idx], Some(prefix.to_owned()))
+            }
+            None => (source, None),
+        };
+        let (source, wildcard) = match source.strip_prefix("*.") {
+            Some(source) if path.is_none() => (source, true),
+            _ => (source, false),
+        };
+        PairSource {
+            host: source.to_owned(),
+            wildcard,
+            path,
+            query,
+        }
+    }
+}
+
+/// Remove a trailing # comment from a line of a pairs file. The # must follow
+/// whitespace, so that one within a destination URL is kept.
+pub(crate) fn strip_comment(line: &str) -> &str {
+    let bytes = line.as_bytes();
+    match (1..bytes.len()).find(|&idx| bytes[idx] == b'#' && bytes[idx - 1].is_ascii_whitespace()) {
+        Some(idx) => &line[..idx],
+        None => line,
+    }
+}
+
+/// Parse newline-delimited domain pairs, skipping blank lines and # comments
+pub(crate) fn parse_pairs(contents: &str) -> anyhow::Result<Vec<DomainPair>> {
+    let mut pairs = vec![];
+    for (idx, line) in contents.lines().enumerate() {
+        let line = strip_comment(line).trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match DomainPair::parse_option(line) {
+            Some(pair) => pairs.push(pair),
+            None => anyhow::bail!("Invalid domain pair on line {}: {}", idx + 1, line),
+        }
+    }
+    Ok(pairs)
+}
+
+pub(crate) fn read_pairs_stdin() -> anyhow::Result<Vec<DomainPair>> {
+    let mut contents = String::new();
+    std::io::Read::read_to_string(&mut std::io::stdin(), &mut contents)
+        .context("Unable to read pairs from stdin")?;
+    parse_pairs(&contents).context("Invalid pairs on stdin")
+}
+
+/// One entry in the JSON array served for --pairs-url
+#[derive(serde::Deserialize)]
+pub(crate) struct RemotePair {
+    pub(crate) source: String,
+    pub(crate) dest: String,
+}
+
+pub(crate) const PAIRS_URL_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub(crate) async fn fetch_pairs(url: &Uri) -> anyhow::Result<Vec<DomainPair>> {
+    let client = hyper::Client::new();
+    let res = tokio::time::timeout(PAIRS_URL_TIMEOUT, client.get(url.clone()))
+        .await
+        .context("Timed out connecting")??;
+    if !res.status().is_success() {
+        anyhow::bail!("Unexpected status {}", res.status());
+    }
+    let body = tokio::time::timeout(PAIRS_URL_TIMEOUT, hyper::body::to_bytes(res.into_body()))
+        .await
+        .context("Timed out reading the response")??;
+    let pairs: Vec<RemotePair> =
+        serde_json::from_slice(&body).context("Expected a JSON array of {source, dest} objects")?;
+    pairs
+        .into_iter()
+        .enumerate()
+        .map(|(idx, RemotePair { source, dest })| {
+            format!("{}={}", source, dest)
+                .parse()
+                .with_context(|| format!("Invalid pair at index {}", idx))
+        })
+        .collect()
+}
+
+pub(crate) const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Fetch pairs, retrying with exponential backoff so that a brief outage of
+/// whatever serves them doesn't abort startup
+pub(crate) async fn fetch_pairs_retrying(
+    url: &Uri,
+    retries: u32,
+) -> anyhow::Result<Vec<DomainPair>> {
+    let mut delay = Duration::from_secs(1);
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        match fetch_pairs(url).await {
+            Ok(pairs) => break Ok(pairs),
+            Err(e) if attempt <= retries => {
+                eprintln!(
+                    "Attempt {} of {} to load pairs from {} failed, retrying in {}s: {:?}",
+                    attempt,
+                    retries + 1,
+                    url,
+                    delay.as_secs(),
+                    e
+                );
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_RETRY_DELAY);
+            }
+            Err(e) => break Err(e),
+        }
+    }
+}
+
+pub(crate) fn read_pairs_file(path: &Path) -> anyhow::Result<Vec<DomainPair>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Unable to read pairs file {}", path.display()))?;
+    parse_pairs(&contents).with_context(|| format!("Invalid pairs file {}", path.display()))
+}
+
+/// Build the routing table from the command line pairs plus the pairs file
+pub(crate) fn load_rules(
+    cli_pairs: &[DomainPair],
+    pairs_file: Option<&Path>,
+    fallback: Option<String>,
+    on_duplicate: OnDuplicate,
+) -> anyhow::Result<Rules> {
+    let mut pairs = cli_pairs.to_vec();
+    if let Some(pairs_file) = pairs_file {
+        pairs.extend(read_pairs_file(pairs_file)?);
+    }
+    Rules::new(pairs, fallback, on_duplicate)
+}
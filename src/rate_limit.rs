@@ -0,0 +1,78 @@
+//! Per-client rate limiting for --rate-limit
+
+use std::{
+    collections::HashMap,
+    hash::{Hash, Hasher},
+    net::IpAddr,
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+/// Per-client token bucket rate limiting, keyed by IP address.
+///
+/// Buckets are split across several independently locked shards to reduce
+/// contention, and idle buckets are periodically swept to bound memory use.
+pub(crate) struct RateLimiter {
+    pub(crate) per_minute: f64,
+    pub(crate) shards: Vec<Mutex<HashMap<IpAddr, Bucket>>>,
+}
+
+pub(crate) struct Bucket {
+    pub(crate) tokens: f64,
+    pub(crate) updated: Instant,
+}
+
+pub(crate) const RATE_LIMIT_SHARDS: usize = 16;
+
+impl RateLimiter {
+    pub(crate) fn new(per_minute: u32) -> Self {
+        RateLimiter {
+            per_minute: per_minute.into(),
+            shards: (0..RATE_LIMIT_SHARDS)
+                .map(|_| Mutex::new(HashMap::new()))
+                .collect(),
+        }
+    }
+
+    pub(crate) fn shard(&self, ip: IpAddr) -> &Mutex<HashMap<IpAddr, Bucket>> {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        ip.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % self.shards.len()]
+    }
+
+    /// Take a token for this client, or return how long until one is available
+    pub(crate) fn check(&self, ip: IpAddr) -> Result<(), Duration> {
+        let per_second = self.per_minute / 60.0;
+        let now = Instant::now();
+        let mut shard = self
+            .shard(ip)
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let bucket = shard.entry(ip).or_insert(Bucket {
+            tokens: self.per_minute,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * per_second).min(self.per_minute);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / per_second))
+        }
+    }
+
+    /// Forget clients idle long enough for their bucket to have refilled
+    pub(crate) fn sweep(&self) {
+        let now = Instant::now();
+        for shard in &self.shards {
+            shard
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .retain(|_, bucket| now.duration_since(bucket.updated) < RATE_LIMIT_WINDOW);
+        }
+    }
+}
+
+pub(crate) const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
@@ -0,0 +1,137 @@
+//! Building responses and their bodies
+
+use std::borrow::Cow;
+
+use flate2::{write::GzEncoder, Compression};
+use hyper::{
+    body::HttpBody,
+    header::{HeaderName, HeaderValue, ACCEPT_ENCODING, CONTENT_LENGTH},
+    Body, Request, Response, StatusCode,
+};
+
+/// Bodies at or below this size aren't worth compressing
+pub(crate) const COMPRESS_THRESHOLD: usize = 256;
+
+pub(crate) fn accepts_gzip(req: &Request<Body>) -> bool {
+    req.headers()
+        .get_all(ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|encoding| {
+            let mut parts = encoding.split(';');
+            let name = parts.next().unwrap_or("").trim();
+            let rejected = parts.any(|param| param.trim().replace(' ', "") == "q=0");
+            name.eq_ignore_ascii_case("gzip") && !rejected
+        })
+}
+
+pub(crate) fn gzip(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    use std::io::Write;
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+/// A page telling the user they are leaving, which refreshes to the
+/// destination after the delay and links to it for those without meta refresh
+pub(crate) fn interstitial_page(delay: u64, location: &str) -> String {
+    let location = escape_html(location);
+    format!(
+        "<!DOCTYPE html>\n\
+         <html>\n\
+         <head>\n\
+         <meta charset=\"utf-8\">\n\
+         <meta http-equiv=\"refresh\" content=\"{delay}; url={location}\">\n\
+         <title>You are leaving this site</title>\n\
+         </head>\n\
+         <body>\n\
+         <p>You are leaving this site. You will be redirected to \
+         <a href=\"{location}\">{location}</a> in {delay} seconds.</p>\n\
+         </body>\n\
+         </html>\n",
+        delay = delay,
+        location = location,
+    )
+}
+
+/// Escape text for inclusion in HTML content or attribute values
+pub(crate) fn escape_html(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+pub(crate) fn make_response(
+    code: StatusCode,
+    body: impl Into<Body>,
+    headers: impl IntoIterator<Item = (HeaderName, HeaderValue)>,
+) -> Response<Body> {
+    let mut res = Response::new(body.into());
+    *res.status_mut() = code;
+    for (name, value) in headers {
+        res.headers_mut().insert(name, value);
+    }
+    res
+}
+
+/// Cut a path and query short, without copying it if it's still borrowed
+pub(crate) fn truncate(path: &mut Cow<str>, len: usize) {
+    match path {
+        Cow::Borrowed(borrowed) => *borrowed = &borrowed[..len],
+        Cow::Owned(owned) => owned.truncate(len),
+    }
+}
+
+/// Collapse runs of slashes in the path portion of a path and query
+pub(crate) fn collapse_slashes(path: &mut Cow<str>) {
+    let end = path.find('?').unwrap_or_else(|| path.len());
+    if !path[..end].contains("//") {
+        return;
+    }
+    let mut collapsed = String::with_capacity(path.len());
+    for c in path[..end].chars() {
+        if c != '/' || !collapsed.ends_with('/') {
+            collapsed.push(c);
+        }
+    }
+    collapsed.push_str(&path[end..]);
+    *path = Cow::Owned(collapsed);
+}
+
+/// Where a fragment starts in a path and query, whether as a literal # or one
+/// percent-encoded in the path by a misbehaving upstream
+pub(crate) fn fragment_start(path: &str) -> Option<usize> {
+    if let Some(idx) = path.find('#') {
+        return Some(idx);
+    }
+    let end = path.find('?').unwrap_or(path.len());
+    path.as_bytes()[..end]
+        .windows(3)
+        .position(|window| window.eq_ignore_ascii_case(b"%23"))
+}
+
+/// Drop the body of a response to a HEAD request.
+///
+/// Hyper would skip sending it anyway, but we don't want to rely on that. The
+/// Content-Length stays what it would have been for a GET, except that a 204
+/// must not have one at all.
+pub(crate) fn without_body(mut res: Response<Body>) -> Response<Body> {
+    if let Some(len) = res.body().size_hint().exact() {
+        if res.status() != StatusCode::NO_CONTENT {
+            res.headers_mut()
+                .insert(CONTENT_LENGTH, HeaderValue::from(len));
+        }
+    }
+    *res.body_mut() = Body::empty();
+    res
+}
@@ -0,0 +1,508 @@
+//! The routing table built from the pairs, and looking up the rule for a request
+
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    str::FromStr,
+    sync::atomic::AtomicU64,
+};
+
+use anyhow::Context;
+use hyper::{StatusCode, Uri};
+
+use crate::{
+    app::path_and_query,
+    host::{normalize_host, strip_port},
+    pairs::{Destination, DomainPair, PairSource, Split},
+};
+
+/// The routing table, replaced wholesale when reloading the pairs file.
+///
+/// Rules are evaluated in the order they were given, and the first match wins,
+/// so that precedence is under the control of whoever writes the pairs. The
+/// exceptions are that a port-qualified pair beats one for the bare host, and
+/// the * catch-all is only used once nothing else, including the rewrites, has
+/// matched. Exact host matches are found through a HashMap instead of a scan,
+/// so a request only walks the wildcard and path prefix rules that were given
+/// before its exact match.
+pub(crate) struct Rules {
+    /// Every rule, in evaluation order
+    pub(crate) rules: Vec<Rule>,
+    /// Index of the exact rule for each normalized host, including any port
+    pub(crate) exact: HashMap<Vec<u8>, usize>,
+    /// Indices of the wildcard and path prefix rules, in evaluation order
+    pub(crate) patterns: Vec<usize>,
+    /// Index of the *=dest pair, used for any host without a more specific rule
+    pub(crate) catch_all: Option<usize>,
+    /// Normalized hosts with path prefix rules, for --strict-paths
+    pub(crate) path_hosts: HashSet<Vec<u8>>,
+    /// Fallback destination, used as a full URL when it includes a scheme
+    pub(crate) fallback: Option<Destination>,
+}
+
+/// What to do when the same source is given more than once
+#[derive(Clone, Copy)]
+pub(crate) enum OnDuplicate {
+    Error,
+    /// Keep the first pair and ignore later ones
+    First,
+    /// Use the last pair's destination, in the position of the first
+    Last,
+}
+
+impl FromStr for OnDuplicate {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(OnDuplicate::Error),
+            "first" => Ok(OnDuplicate::First),
+            "last" => Ok(OnDuplicate::Last),
+            _ => Err(anyhow::anyhow!(
+                "Invalid duplicate handling {}, must be error, first, or last",
+                s
+            )),
+        }
+    }
+}
+
+/// What a rule matches against
+#[derive(PartialEq)]
+pub(crate) enum Source {
+    /// A single normalized host
+    Exact(Vec<u8>),
+    /// Any single-label subdomain of this parent domain (e.g. example.com for
+    /// *.example.com)
+    Wildcard(Vec<u8>),
+    /// A path prefix without a trailing slash, on a normalized host or any host
+    /// if None
+    Prefix {
+        host: Option<Vec<u8>>,
+        prefix: String,
+    },
+    /// Any host at all
+    CatchAll,
+}
+
+impl std::fmt::Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Source::Exact(host) => write!(f, "{}", String::from_utf8_lossy(host)),
+            Source::Wildcard(host) => write!(f, "*.{}", String::from_utf8_lossy(host)),
+            Source::Prefix { host, prefix } => write!(
+                f,
+                "{}{}",
+                host.as_deref()
+                    .map(String::from_utf8_lossy)
+                    .unwrap_or_default(),
+                prefix
+            ),
+            Source::CatchAll => write!(f, "*"),
+        }
+    }
+}
+
+/// A query parameter a rule requires, compared without percent-decoding
+#[derive(Clone, PartialEq)]
+pub(crate) struct QueryMatch {
+    pub(crate) name: String,
+    /// The required value, or None to accept any
+    pub(crate) value: Option<String>,
+}
+
+impl QueryMatch {
+    pub(crate) fn parse(s: &str) -> Self {
+        match s.split_once('=') {
+            Some((name, value)) => QueryMatch {
+                name: name.to_owned(),
+                value: Some(value.to_owned()),
+            },
+            None => QueryMatch {
+                name: s.to_owned(),
+                value: None,
+            },
+        }
+    }
+
+    pub(crate) fn matches(&self, query: Option<&str>) -> bool {
+        query.map_or(false, |query| {
+            query.split('&').any(|param| {
+                let (name, value) = param.split_once('=').unwrap_or((param, ""));
+                name == self.name
+                    && self
+                        .value
+                        .as_deref()
+                        .map_or(true, |expected| expected == value)
+            })
+        })
+    }
+}
+
+pub(crate) struct Rule {
+    pub(crate) source: Source,
+    /// Query parameter required for this rule to match
+    pub(crate) query: Option<QueryMatch>,
+    pub(crate) dest: Destination,
+    /// Weighted destinations to choose between instead of dest
+    pub(crate) split: Option<Split>,
+    /// Redirect status for this rule, overriding --status, or a status other than
+    /// 3xx to respond with instead of redirecting
+    pub(crate) status: Option<StatusCode>,
+    /// Replacement for the matched path prefix, or None to keep the path unchanged
+    pub(crate) replacement: Option<String>,
+    /// Number of requests matched to this rule
+    pub(crate) hits: AtomicU64,
+}
+
+impl std::fmt::Display for Rule {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.source)?;
+        match &self.query {
+            Some(QueryMatch {
+                name,
+                value: Some(value),
+            }) => write!(f, "?{}={}", name, value),
+            Some(QueryMatch { name, value: None }) => write!(f, "?{}", name),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Rule {
+    /// Whether this rule responds with its status instead of redirecting
+    pub(crate) fn responds(&self) -> bool {
+        self.status.map_or(false, |status| !status.is_redirection())
+    }
+
+    /// The destination for a single request
+    pub(crate) fn choose(&self) -> &Destination {
+        match &self.split {
+            Some(split) => split.choose(),
+            None => &self.dest,
+        }
+    }
+
+    /// Whether this rule matches the normalized host, path, and query
+    pub(crate) fn matches(&self, host: &[u8], path: &str, query: Option<&str>) -> bool {
+        if let Some(expected) = &self.query {
+            if !expected.matches(query) {
+                return false;
+            }
+        }
+        match &self.source {
+            Source::Exact(expected) => expected.as_slice() == host,
+            Source::Wildcard(parent) => match host.iter().position(|&b| b == b'.') {
+                Some(dot) => &host[dot + 1..] == parent.as_slice(),
+                None => false,
+            },
+            Source::Prefix {
+                host: expected,
+                prefix,
+            } => {
+                if let Some(expected) = expected {
+                    if expected.as_slice() != host {
+                        return false;
+                    }
+                }
+                match path.strip_prefix(prefix.as_str()) {
+                    Some(rest) => rest.is_empty() || rest.starts_with('/'),
+                    None => false,
+                }
+            }
+            Source::CatchAll => true,
+        }
+    }
+
+    /// Compute the path and query to append to the destination
+    pub(crate) fn rewrite<'a>(&self, uri: &'a Uri) -> Cow<'a, str> {
+        let prefix = match (&self.source, &self.replacement) {
+            (Source::Prefix { prefix, .. }, Some(_)) => prefix,
+            _ => return Cow::Borrowed(path_and_query(uri)),
+        };
+        let query = match uri.query() {
+            Some(query) => format!("?{}", query),
+            None => String::new(),
+        };
+        let rest = &uri.path()[prefix.len()..];
+        let path = format!("{}{}", self.replacement.as_deref().unwrap_or(""), rest);
+        if path.is_empty() {
+            format!("/{}", query).into()
+        } else {
+            format!("{}{}", path, query).into()
+        }
+    }
+}
+
+impl Rules {
+    pub(crate) fn new(
+        pairs: Vec<DomainPair>,
+        fallback: Option<String>,
+        on_duplicate: OnDuplicate,
+    ) -> anyhow::Result<Self> {
+        let mut rules: Vec<Rule> = vec![];
+        let mut exact = HashMap::new();
+        let mut patterns = vec![];
+        let mut catch_all = None;
+        let mut path_hosts = HashSet::new();
+        let pairs = pairs.into_iter().flat_map(
+            |DomainPair {
+                 sources,
+                 dest,
+                 split,
+                 status,
+             }| {
+                sources
+                    .into_iter()
+                    .map(move |source| (source, dest.clone(), split.clone(), status))
+            },
+        );
+        for (
+            PairSource {
+                host: source,
+                wildcard,
+                path,
+                query,
+            },
+            dest,
+            split,
+            status,
+        ) in pairs
+        {
+            if source.is_empty() && path.is_none() {
+                anyhow::bail!("Empty source domain name for destination {}", dest.host);
+            }
+            let key = normalize_host(source.as_bytes())
+                .with_context(|| format!("Invalid domain name {}", source))?;
+            if split.is_some() && path.is_some() {
+                anyhow::bail!(
+                    "Weighted destinations are not supported for path {}",
+                    source
+                );
+            }
+            let choices = match &split {
+                Some(split) => split.choices.iter().map(|(_, dest)| dest).collect(),
+                // A pair that responds without redirecting has no destination
+                None if status.map_or(false, |status| !status.is_redirection()) => vec![],
+                None => vec![&dest],
+            };
+            for choice in choices {
+                if choice.host.is_empty() {
+                    anyhow::bail!("Empty destination for {}", source);
+                }
+                choice.validate()?;
+                if path.is_none() && !wildcard && choice.host.as_bytes().eq_ignore_ascii_case(&key)
+                {
+                    anyhow::bail!("Domain name {} redirects to itself", source);
+                }
+            }
+            let (source, dest, replacement) = match path {
+                Some(prefix) => {
+                    let host = if source.is_empty() { None } else { Some(key) };
+                    let (dest, replacement) = match dest.host.find('/') {
+                        Some(idx) => (
+                            Destination {
+                                scheme: dest.scheme,
+                                host: dest.host[..idx].to_owned(),
+                            },
+                            Some(dest.host[idx..].trim_end_matches('/').to_owned()),
+                        ),
+                        None => (dest, None),
+                    };
+                    (Source::Prefix { host, prefix }, dest, replacement)
+                }
+                None if wildcard => (Source::Wildcard(key), dest, None),
+                None if source == "*" => (Source::CatchAll, dest, None),
+                None => (Source::Exact(key), dest, None),
+            };
+            // Rules with a query parameter are scanned in order like the patterns,
+            // since they can't be looked up by host alone
+            let duplicate = match &source {
+                Source::Exact(key) if query.is_none() => exact.get(key).copied(),
+                Source::CatchAll if query.is_none() => catch_all,
+                _ => patterns
+                    .iter()
+                    .copied()
+                    .find(|&idx: &usize| rules[idx].source == source && rules[idx].query == query),
+            };
+            let rule = Rule {
+                source,
+                query,
+                dest,
+                split,
+                status,
+                replacement,
+                hits: AtomicU64::new(0),
+            };
+            match (duplicate, on_duplicate) {
+                (None, _) => (),
+                (Some(_), OnDuplicate::Error) => {
+                    anyhow::bail!("Duplicate destination for {}", rule)
+                }
+                (Some(_), OnDuplicate::First) => {
+                    eprintln!("Ignoring duplicate pair for {}, keeping the first", rule);
+                    continue;
+                }
+                (Some(idx), OnDuplicate::Last) => {
+                    eprintln!("Replacing earlier pair for {} with a later one", rule);
+                    rules[idx] = rule;
+                    continue;
+                }
+            }
+            let idx = rules.len();
+            match &rule.source {
+                Source::Exact(key) if rule.query.is_none() => {
+                    exact.insert(key.clone(), idx);
+                }
+                Source::CatchAll if rule.query.is_none() => catch_all = Some(idx),
+                _ => patterns.push(idx),
+            }
+            if let Source::Prefix {
+                host: Some(host), ..
+            } = &rule.source
+            {
+                path_hosts.insert(host.clone());
+            }
+            rules.push(rule);
+        }
+        let fallback = fallback.as_deref().map(Destination::parse);
+        if let Some(fallback) = &fallback {
+            fallback.validate()?;
+        }
+        Ok(Rules {
+            rules,
+            exact,
+            patterns,
+            catch_all,
+            path_hosts,
+            fallback,
+        })
+    }
+
+    /// Print the routing table in evaluation order, for --check
+    pub(crate) fn print(&self, default_scheme: &str) {
+        let scheme = |dest: &Destination| dest.scheme.unwrap_or(default_scheme).to_owned();
+        let catch_all = self.catch_all.map(|idx| &self.rules[idx]);
+        let rules = self
+            .rules
+            .iter()
+            .filter(|rule| rule.source != Source::CatchAll || rule.query.is_some())
+            .chain(catch_all);
+        for rule in rules {
+            match &rule.split {
+                Some(split) => {
+                    let mut previous = 0;
+                    let choices = split
+                        .choices
+                        .iter()
+                        .map(|(total, dest)| {
+                            let weight = total - previous;
+                            previous = *total;
+                            format!("{}% {}://{}", weight, scheme(dest), dest.host)
+                        })
+                        .collect::<Vec<_>>();
+                    println!("{} -> {}", rule, choices.join(", "));
+                }
+                None if rule.responds() => {
+                    println!("{} -> {}", rule, rule.status.unwrap_or(StatusCode::GONE))
+                }
+                None => println!(
+                    "{} -> {}://{}{}",
+                    rule,
+                    scheme(&rule.dest),
+                    rule.dest.host,
+                    rule.replacement.as_deref().unwrap_or("")
+                ),
+            }
+        }
+        match &self.fallback {
+            Some(fallback) => println!("fallback -> {}://{}", scheme(fallback), fallback.host),
+            None => println!("no fallback"),
+        }
+    }
+
+    /// Describe each exact pair that overlaps a wildcard pair, and which one wins,
+    /// along with the query and path pairs that an earlier exact pair for the
+    /// same host keeps from ever matching
+    pub(crate) fn overlaps(&self) -> Vec<String> {
+        let mut overlaps = vec![];
+        for (exact_idx, exact) in self.rules.iter().enumerate() {
+            let key = match &exact.source {
+                Source::Exact(key) if exact.query.is_none() => key,
+                _ => continue,
+            };
+            // Lookups stop scanning at the exact match for the host
+            for &idx in self.patterns.iter().filter(|&&idx| idx > exact_idx) {
+                let rule = &self.rules[idx];
+                let same_host = match &rule.source {
+                    Source::Exact(host) => host == key,
+                    Source::Prefix {
+                        host: Some(host), ..
+                    } => host == key,
+                    _ => false,
+                };
+                if same_host {
+                    overlaps.push(format!(
+                        "{} comes first and shadows {}, which will never match",
+                        exact.source, rule
+                    ));
+                }
+            }
+            let host = strip_port(key);
+            let parent = match host.iter().position(|&b| b == b'.') {
+                Some(dot) => &host[dot + 1..],
+                None => continue,
+            };
+            for &wildcard_idx in &self.patterns {
+                let wildcard = &self.rules[wildcard_idx];
+                if wildcard.source != Source::Wildcard(parent.to_vec()) || wildcard.query.is_some()
+                {
+                    continue;
+                }
+                overlaps.push(if exact_idx < wildcard_idx {
+                    format!("{} shadows {} for that host", exact.source, wildcard.source)
+                } else {
+                    format!(
+                        "{} comes first and shadows {}, which will never match",
+                        wildcard.source, exact.source
+                    )
+                });
+            }
+        }
+        overlaps
+    }
+
+    /// Total number of pairs loaded, across every kind of rule
+    pub(crate) fn pair_count(&self) -> usize {
+        self.rules.len()
+    }
+
+    /// Find the first rule matching a normalized host, path, and query, not
+    /// counting the catch-all.
+    ///
+    /// The authority is the normalized host including its port, when the request
+    /// gave one.
+    pub(crate) fn lookup(
+        &self,
+        host: &[u8],
+        authority: Option<&[u8]>,
+        path: &str,
+        query: Option<&str>,
+    ) -> Option<&Rule> {
+        let exact = authority
+            .and_then(|authority| self.exact.get(authority))
+            .or_else(|| self.exact.get(host))
+            .copied();
+        let pattern = self
+            .patterns
+            .iter()
+            .copied()
+            .take_while(|&idx| exact.map_or(true, |exact| idx < exact))
+            .find(|&idx| self.rules[idx].matches(host, path, query));
+        pattern.or(exact).map(|idx| &self.rules[idx])
+    }
+
+    /// The *=dest pair, if there is one
+    pub(crate) fn catch_all(&self) -> Option<&Rule> {
+        self.catch_all.map(|idx| &self.rules[idx])
+    }
+}
@@ -0,0 +1,470 @@
+//! Listening on TCP, Unix sockets, or inherited descriptors, and signal handling
+
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::{
+    convert::Infallible,
+    future::Future,
+    net::SocketAddr,
+    path::Path,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+
+use anyhow::Context;
+use futures::future::{try_join_all, Either};
+use hyper::{
+    server::{
+        accept::Accept,
+        conn::{AddrIncoming, AddrStream},
+    },
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::watch,
+};
+use tokio_rustls::server::TlsStream;
+
+use crate::{app::App, tls::tls_incoming};
+#[cfg(unix)]
+use crate::{
+    pairs::{load_rules, DomainPair},
+    rules::OnDuplicate,
+};
+
+/// Wait for SIGINT (Ctrl-C), or SIGTERM on Unix
+pub(crate) async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = sigterm.recv() => {}
+                }
+            }
+            Err(e) => {
+                eprintln!("Unable to listen for SIGTERM: {:?}", e);
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+/// Returns a receiver that is notified once a shutdown signal arrives
+pub(crate) fn spawn_shutdown_listener() -> watch::Receiver<()> {
+    let (tx, rx) = watch::channel(());
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        eprintln!("Received shutdown signal, draining in-flight requests");
+        let _ = tx.send(());
+    });
+    rx
+}
+
+pub(crate) async fn wait_for_shutdown(mut rx: watch::Receiver<()>) {
+    let _ = rx.changed().await;
+}
+
+/// How often to check whether the --maintenance-file exists
+const MAINTENANCE_FILE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Switch maintenance mode on and off as the --maintenance-file appears and
+/// disappears
+pub(crate) fn spawn_maintenance_watcher(app: Arc<App>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(MAINTENANCE_FILE_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Some(maintenance) = &app.maintenance {
+                if let Some(file) = &maintenance.file {
+                    let enabled = file.exists();
+                    if maintenance.enabled.swap(enabled, Ordering::Relaxed) != enabled {
+                        eprintln!(
+                            "Maintenance mode {}",
+                            if enabled { "enabled" } else { "disabled" }
+                        );
+                    }
+                }
+            }
+        }
+    });
+}
+
+/// Turn on verbose logging whenever we receive SIGUSR1, and go back to the
+/// verbosity from the command line on SIGUSR2, for debugging without a restart
+#[cfg(unix)]
+pub(crate) fn spawn_verbose_listener(app: Arc<App>) -> anyhow::Result<()> {
+    use crate::log::VERBOSE;
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut sigusr1 =
+        signal(SignalKind::user_defined1()).context("Unable to listen for SIGUSR1")?;
+    let mut sigusr2 =
+        signal(SignalKind::user_defined2()).context("Unable to listen for SIGUSR2")?;
+    tokio::spawn(async move {
+        loop {
+            let verbosity = tokio::select! {
+                Some(()) = sigusr1.recv() => VERBOSE,
+                Some(()) = sigusr2.recv() => app.default_verbosity,
+                else => break,
+            };
+            app.verbosity.store(verbosity, Ordering::Relaxed);
+            eprintln!(
+                "Verbose logging {}",
+                if verbosity == VERBOSE {
+                    "enabled"
+                } else {
+                    "disabled"
+                }
+            );
+        }
+    });
+    Ok(())
+}
+
+/// Reload the routing table from the pairs file whenever we receive SIGHUP.
+///
+/// If the new table fails to load, the previous one stays in place.
+#[cfg(unix)]
+pub(crate) fn spawn_reload_listener(
+    app: Arc<App>,
+    cli_pairs: Vec<DomainPair>,
+    pairs_file: PathBuf,
+    fallback: Option<String>,
+    on_duplicate: OnDuplicate,
+) -> anyhow::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut sighup = signal(SignalKind::hangup()).context("Unable to listen for SIGHUP")?;
+    tokio::spawn(async move {
+        while sighup.recv().await.is_some() {
+            match load_rules(
+                &cli_pairs,
+                Some(&pairs_file),
+                fallback.clone(),
+                on_duplicate,
+            ) {
+                Ok(rules) => {
+                    eprintln!("Reloaded pairs from {}", pairs_file.display());
+                    app.rules.store(Arc::new(rules));
+                }
+                Err(e) => eprintln!(
+                    "Unable to reload pairs, keeping previous configuration: {:?}",
+                    e
+                ),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// The address to bind to when no --bind is given, using $PORT if set
+pub(crate) fn default_bind() -> anyhow::Result<SocketAddr> {
+    let port = match std::env::var("PORT") {
+        Ok(port) => port
+            .parse()
+            .with_context(|| format!("Invalid PORT environment variable {}", port))?,
+        Err(_) => 3000,
+    };
+    Ok(SocketAddr::from(([0, 0, 0, 0], port)))
+}
+
+pub(crate) fn bind_tcp(addr: &SocketAddr, app: &App) -> anyhow::Result<AddrIncoming> {
+    let listen = || -> std::io::Result<tokio::net::TcpListener> {
+        use socket2::{Domain, Protocol, Socket, Type};
+        let socket = Socket::new(
+            Domain::for_address(*addr),
+            Type::STREAM,
+            Some(Protocol::TCP),
+        )?;
+        // Matching what AddrIncoming::bind does, so restarts don't hit "address in use"
+        #[cfg(not(windows))]
+        {
+            socket.set_reuse_address(true)?;
+        }
+        if app.reuse_port {
+            // SO_REUSEPORT doesn't exist elsewhere, so the flag is ignored there
+            #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+            socket.set_reuse_port(true)?;
+        }
+        socket.set_nonblocking(true)?;
+        socket.bind(&(*addr).into())?;
+        socket.listen(app.backlog)?;
+        tokio::net::TcpListener::from_std(socket.into())
+    };
+    let listener = listen().with_context(|| format!("Unable to bind to {}", addr))?;
+    let mut incoming = AddrIncoming::from_listener(listener)
+        .with_context(|| format!("Unable to bind to {}", addr))?;
+    set_keepalive(&mut incoming, app);
+    Ok(incoming)
+}
+
+/// Send TCP keepalive probes on accepted connections, if --keep-alive asks for them
+fn set_keepalive(incoming: &mut AddrIncoming, app: &App) {
+    if let Some(interval) = app.keep_alive.filter(|interval| !interval.is_zero()) {
+        incoming.set_keepalive(Some(interval));
+    }
+}
+
+/// Serve on all of the given TCP addresses, failing if any can't be bound
+pub(crate) async fn serve_tcp(
+    binds: &[SocketAddr],
+    app: Arc<App>,
+    shutdown: watch::Receiver<()>,
+) -> anyhow::Result<()> {
+    let incomings = binds
+        .iter()
+        .map(|addr| bind_tcp(addr, &app))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    try_join_all(incomings.into_iter().map(|incoming| match &app.tls {
+        Some(acceptor) => Either::Left(serve(
+            tls_incoming(incoming, acceptor.clone()),
+            app.clone(),
+            App::handle,
+            shutdown.clone(),
+        )),
+        None => Either::Right(serve(incoming, app.clone(), App::handle, shutdown.clone())),
+    }))
+    .await
+    .context("Hyper server exited unexpectedly")?;
+    Ok(())
+}
+
+/// The first listener passed by systemd socket activation, if any
+pub(crate) fn systemd_listen_fd() -> Option<i32> {
+    let pid: u32 = std::env::var("LISTEN_PID").ok()?.parse().ok()?;
+    let fds: u32 = std::env::var("LISTEN_FDS").ok()?.parse().ok()?;
+    if pid == std::process::id() && fds >= 1 {
+        // systemd passes listeners starting after stdin, stdout, and stderr
+        Some(3)
+    } else {
+        None
+    }
+}
+
+/// Serve on an already-bound TCP listener inherited from our parent
+#[cfg(unix)]
+pub(crate) async fn serve_fd(
+    fd: i32,
+    app: Arc<App>,
+    shutdown: watch::Receiver<()>,
+) -> anyhow::Result<()> {
+    use std::os::unix::io::FromRawFd;
+    // Safety: the descriptor was handed to us to listen on, and nothing else in
+    // this process owns it
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+    let context = || format!("Unable to listen on file descriptor {}", fd);
+    listener.set_nonblocking(true).with_context(context)?;
+    let listener = tokio::net::TcpListener::from_std(listener).with_context(context)?;
+    let mut incoming = AddrIncoming::from_listener(listener).with_context(context)?;
+    set_keepalive(&mut incoming, &app);
+    let res = match app.tls.clone() {
+        Some(acceptor) => serve(tls_incoming(incoming, acceptor), app, App::handle, shutdown).await,
+        None => serve(incoming, app, App::handle, shutdown).await,
+    };
+    res.context("Hyper server exited unexpectedly")
+}
+
+#[cfg(not(unix))]
+pub(crate) async fn serve_fd(
+    _fd: i32,
+    _app: Arc<App>,
+    _shutdown: watch::Receiver<()>,
+) -> anyhow::Result<()> {
+    anyhow::bail!("Listening on a file descriptor is not supported on this platform")
+}
+
+/// Serve HTTPS with the ACME certificate on --acme-bind, if there is one
+#[cfg(feature = "acme")]
+pub(crate) async fn serve_acme(app: Arc<App>, shutdown: watch::Receiver<()>) -> anyhow::Result<()> {
+    let acme = match &app.acme {
+        Some(acme) => acme,
+        None => return Ok(()),
+    };
+    let incoming = bind_tcp(&acme.bind, &app)?;
+    serve(
+        tls_incoming(incoming, acme.acceptor.clone()),
+        app.clone(),
+        App::handle,
+        shutdown,
+    )
+    .await
+    .context("HTTPS server exited unexpectedly")
+}
+
+#[cfg(not(feature = "acme"))]
+pub(crate) async fn serve_acme(
+    _app: Arc<App>,
+    _shutdown: watch::Receiver<()>,
+) -> anyhow::Result<()> {
+    Ok(())
+}
+
+/// Serve requests from the given listener with the handler until shutdown
+pub(crate) async fn serve<I, IO, IE, H, F>(
+    incoming: I,
+    app: Arc<App>,
+    handler: H,
+    shutdown: watch::Receiver<()>,
+) -> hyper::Result<()>
+where
+    I: Accept<Conn = IO, Error = IE>,
+    IE: Into<Box<dyn std::error::Error + Send + Sync>>,
+    IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    IO: PeerAddr,
+    H: Fn(Arc<App>, Request<Body>, Option<SocketAddr>) -> F + Copy + Send + 'static,
+    F: Future<Output = Result<Response<Body>, Infallible>> + Send + 'static,
+{
+    let request_timeout = app.request_timeout;
+    let http2 = app.http2;
+    let keep_alive = app.keep_alive;
+    let make_svc = make_service_fn(move |conn: &IO| {
+        let remote = conn.peer_addr();
+        let tls = conn.is_tls();
+        let conn = Connection::new(app.clone());
+        async move {
+            Ok::<_, Infallible>(service_fn(move |mut req: Request<Body>| {
+                if tls {
+                    req.extensions_mut().insert(InboundTls);
+                }
+                handler(conn.app.clone(), req, remote)
+            }))
+        }
+    });
+    let mut builder = Server::builder(incoming);
+    if let Some(timeout) = request_timeout {
+        // Reap connections that never finish sending their headers
+        builder = builder.http1_header_read_timeout(timeout);
+    }
+    if http2 {
+        builder = builder.http2_only(true);
+    }
+    match keep_alive {
+        None => (),
+        Some(interval) if interval.is_zero() => builder = builder.http1_keepalive(false),
+        Some(interval) => {
+            builder = builder
+                .http2_keep_alive_interval(interval)
+                .http2_keep_alive_timeout(interval)
+        }
+    }
+    builder
+        .serve(make_svc)
+        .with_graceful_shutdown(wait_for_shutdown(shutdown))
+        .await
+}
+
+/// Counts a connection as open for as long as its service is alive
+pub(crate) struct Connection {
+    pub(crate) app: Arc<App>,
+}
+
+impl Connection {
+    pub(crate) fn new(app: Arc<App>) -> Self {
+        app.connections.fetch_add(1, Ordering::Relaxed);
+        Connection { app }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.app.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Connections that may know the address of the client on the other end
+pub(crate) trait PeerAddr {
+    fn peer_addr(&self) -> Option<SocketAddr>;
+
+    /// Whether we terminated TLS on this connection
+    fn is_tls(&self) -> bool {
+        false
+    }
+}
+
+/// Request extension marking one received over TLS we terminated ourselves
+pub(crate) struct InboundTls;
+
+impl PeerAddr for AddrStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        Some(self.remote_addr())
+    }
+}
+
+impl PeerAddr for TlsStream<AddrStream> {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.get_ref().0.peer_addr()
+    }
+
+    fn is_tls(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(unix)]
+impl PeerAddr for tokio::net::UnixStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+#[cfg(unix)]
+pub(crate) struct UnixIncoming(tokio::net::UnixListener);
+
+#[cfg(unix)]
+impl Accept for UnixIncoming {
+    type Conn = tokio::net::UnixStream;
+    type Error = std::io::Error;
+
+    fn poll_accept(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Result<Self::Conn, Self::Error>>> {
+        self.0
+            .poll_accept(cx)
+            .map(|res| Some(res.map(|(stream, _addr)| stream)))
+    }
+}
+
+/// Serve on a Unix domain socket, replacing any stale socket file and
+/// removing it again after shutdown. Anything at the path other than a socket
+/// is left alone, and we refuse to start.
+#[cfg(unix)]
+pub(crate) async fn serve_unix(
+    path: &Path,
+    app: Arc<App>,
+    shutdown: watch::Receiver<()>,
+) -> anyhow::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)
+            .with_context(|| format!("Unable to remove existing socket {}", path.display()))?,
+        Ok(_) => anyhow::bail!(
+            "Refusing to replace {}, which exists and is not a socket",
+            path.display()
+        ),
+        Err(_) => (),
+    }
+    let listener = tokio::net::UnixListener::bind(path)
+        .with_context(|| format!("Unable to bind to {}", path.display()))?;
+    let res = serve(UnixIncoming(listener), app, App::handle, shutdown).await;
+    if let Err(e) = std::fs::remove_file(path) {
+        eprintln!("Unable to remove socket {}: {:?}", path.display(), e);
+    }
+    res.context("Hyper server exited unexpectedly")
+}
+
+#[cfg(not(unix))]
+pub(crate) async fn serve_unix(
+    _path: &Path,
+    _app: Arc<App>,
+    _shutdown: watch::Receiver<()>,
+) -> anyhow::Result<()> {
+    anyhow::bail!("Unix domain sockets are not supported on this platform")
+}
@@ -0,0 +1,109 @@
+//! Terminating HTTPS with a certificate and key from disk
+
+use std::{path::Path, sync::Arc, time::Duration};
+
+use anyhow::Context;
+use futures::StreamExt;
+use hyper::server::{
+    accept::Accept,
+    conn::{AddrIncoming, AddrStream},
+};
+use tokio_rustls::{rustls, server::TlsStream, TlsAcceptor};
+
+/// Load the certificate chain and private key for serving HTTPS, advertising
+/// whichever HTTP version we speak through ALPN
+pub(crate) fn load_tls(cert: &Path, key: &Path, http2: bool) -> anyhow::Result<TlsAcceptor> {
+    let open = |path: &Path| {
+        std::fs::File::open(path)
+            .map(std::io::BufReader::new)
+            .with_context(|| format!("Unable to open {}", path.display()))
+    };
+    let certs = rustls_pemfile::certs(&mut open(cert)?)
+        .with_context(|| format!("Unable to read certificates from {}", cert.display()))?;
+    if certs.is_empty() {
+        anyhow::bail!("No certificates found in {}", cert.display());
+    }
+    let key = read_private_key(&mut open(key)?)
+        .with_context(|| format!("Unable to read private key from {}", key.display()))?
+        .with_context(|| format!("No private key found in {}", key.display()))?;
+    let mut config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(
+            certs.into_iter().map(rustls::Certificate).collect(),
+            rustls::PrivateKey(key),
+        )
+        .context("Invalid TLS certificate or key")?;
+    config.alpn_protocols = alpn_protocols(http2);
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// The first private key in a PEM file, of any supported type
+pub(crate) fn read_private_key(
+    reader: &mut dyn std::io::BufRead,
+) -> std::io::Result<Option<Vec<u8>>> {
+    loop {
+        match rustls_pemfile::read_one(reader)? {
+            Some(
+                rustls_pemfile::Item::RSAKey(der)
+                | rustls_pemfile::Item::PKCS8Key(der)
+                | rustls_pemfile::Item::ECKey(der),
+            ) => break Ok(Some(der)),
+            Some(_) => (),
+            None => break Ok(None),
+        }
+    }
+}
+
+pub(crate) fn alpn_protocols(http2: bool) -> Vec<Vec<u8>> {
+    vec![if http2 {
+        b"h2".to_vec()
+    } else {
+        b"http/1.1".to_vec()
+    }]
+}
+
+/// Handshakes in progress at once per listener, so a slow client doesn't hold
+/// up the others
+pub(crate) const TLS_HANDSHAKES: usize = 64;
+
+pub(crate) const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Complete a TLS handshake on each connection before handing it to hyper.
+/// Failed handshakes are logged and dropped, since an error from the listener
+/// would stop the server.
+pub(crate) fn tls_incoming(
+    mut incoming: AddrIncoming,
+    acceptor: TlsAcceptor,
+) -> impl Accept<Conn = TlsStream<AddrStream>, Error = std::io::Error> {
+    let streams =
+        futures::stream::poll_fn(move |cx| std::pin::Pin::new(&mut incoming).poll_accept(cx))
+            .map(move |conn| {
+                let acceptor = acceptor.clone();
+                async move {
+                    let conn = match conn {
+                        Ok(conn) => conn,
+                        Err(e) => {
+                            eprintln!("Unable to accept connection: {:?}", e);
+                            return None;
+                        }
+                    };
+                    let remote = conn.remote_addr();
+                    match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(conn)).await {
+                        Ok(Ok(stream)) => Some(stream),
+                        Ok(Err(e)) => {
+                            eprintln!("TLS handshake with {} failed: {:?}", remote, e);
+                            None
+                        }
+                        Err(_) => {
+                            eprintln!("TLS handshake with {} timed out", remote);
+                            None
+                        }
+                    }
+                }
+            })
+            .buffer_unordered(TLS_HANDSHAKES)
+            .filter_map(futures::future::ready)
+            .map(Ok::<_, std::io::Error>);
+    hyper::server::accept::from_stream(streams)
+}
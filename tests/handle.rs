@@ -0,0 +1,557 @@
+//! Requests driven through App::handle in-process, without binding a socket
+
+use std::sync::Arc;
+
+use hyper::{
+    header::{HOST, LOCATION},
+    Body, Request, Response, StatusCode,
+};
+use redirector::{App, DomainPair};
+
+fn app_with(args: &[&str]) -> Arc<App> {
+    App::from_args(["redirector", "--quiet"].iter().chain(args)).expect("configuration is valid")
+}
+
+fn request(host: &str, uri: &str) -> Request<Body> {
+    Request::builder()
+        .uri(uri)
+        .header(HOST, host)
+        .body(Body::empty())
+        .expect("request is valid")
+}
+
+async fn handle(app: &Arc<App>, req: Request<Body>) -> Response<Body> {
+    app.clone()
+        .handle(req, None)
+        .await
+        .expect("handling is infallible")
+}
+
+async fn get(app: &Arc<App>, host: &str, uri: &str) -> Response<Body> {
+    handle(app, request(host, uri)).await
+}
+
+fn location(res: &Response<Body>) -> Option<&str> {
+    res.headers()
+        .get(LOCATION)
+        .map(|location| location.to_str().expect("location is ASCII"))
+}
+
+fn request_with_method(method: hyper::Method, host: &str, uri: &str) -> Request<Body> {
+    Request::builder()
+        .method(method)
+        .uri(uri)
+        .header(HOST, host)
+        .body(Body::empty())
+        .expect("request is valid")
+}
+
+async fn body(res: Response<Body>) -> hyper::body::Bytes {
+    hyper::body::to_bytes(res.into_body())
+        .await
+        .expect("body is readable")
+}
+
+#[tokio::test]
+async fn permanent_redirect_by_default() {
+    let pairs = vec!["old.com=new.com"
+        .parse::<DomainPair>()
+        .expect("pair is valid")];
+    let app = App::new(pairs, None, false).expect("configuration is valid");
+    let res = get(&app, "old.com", "/path").await;
+    assert_eq!(res.status(), StatusCode::PERMANENT_REDIRECT);
+    assert_eq!(location(&res), Some("https://new.com/path"));
+}
+
+#[tokio::test]
+async fn temporary_redirect() {
+    let app = app_with(&["--temporary", "old.com=new.com"]);
+    let res = get(&app, "old.com", "/path").await;
+    assert_eq!(res.status(), StatusCode::TEMPORARY_REDIRECT);
+    assert_eq!(location(&res), Some("https://new.com/path"));
+}
+
+#[tokio::test]
+async fn host_is_case_insensitive() {
+    let app = app_with(&["Example.com=new.com"]);
+    for host in ["example.com", "Example.COM", "EXAMPLE.COM"] {
+        let res = get(&app, host, "/").await;
+        assert_eq!(location(&res), Some("https://new.com/"), "{}", host);
+    }
+}
+
+#[tokio::test]
+async fn port_is_ignored_for_matching() {
+    let app = app_with(&["example.com=new.com", "[::1]=ipv6.example.com"]);
+    for (host, expected) in [
+        ("example.com", "https://new.com/path"),
+        ("example.com:8080", "https://new.com/path"),
+        ("[::1]:8080", "https://ipv6.example.com/path"),
+    ] {
+        let res = get(&app, host, "/path").await;
+        assert_eq!(res.status(), StatusCode::PERMANENT_REDIRECT, "{}", host);
+        assert_eq!(location(&res), Some(expected), "{}", host);
+    }
+}
+
+#[tokio::test]
+async fn query_string_is_kept() {
+    let app = app_with(&["old.com=new.com"]);
+    for uri in ["/path?a=1&b=2", "/path", "/path?"] {
+        let res = get(&app, "old.com", uri).await;
+        assert_eq!(location(&res), Some(&*format!("https://new.com{}", uri)));
+    }
+}
+
+#[tokio::test]
+async fn strip_query() {
+    let app = app_with(&["--strip-query", "old.com=new.com"]);
+    for uri in ["/path?a=1&b=2", "/path", "/path?"] {
+        let res = get(&app, "old.com", uri).await;
+        assert_eq!(location(&res), Some("https://new.com/path"), "{}", uri);
+    }
+}
+
+#[tokio::test]
+async fn suspicious_hosts_are_rejected() {
+    let app = app_with(&["--fallback", "fallback.com", "old.com=new.com"]);
+    for host in [
+        "old.com/evil",
+        "old.com\tevil",
+        "old.com evil",
+        "old.com@evil.com",
+    ] {
+        let res = get(&app, host, "/").await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{:?}", host);
+        assert_eq!(location(&res), None, "{:?}", host);
+    }
+}
+
+#[tokio::test]
+async fn port_is_dropped_by_default() {
+    let app = app_with(&["old.com=new.com"]);
+    let res = get(&app, "old.com:8443", "/").await;
+    assert_eq!(location(&res), Some("https://new.com/"));
+}
+
+#[tokio::test]
+async fn preserve_port() {
+    let app = app_with(&["--preserve-port", "old.com=new.com"]);
+    for (host, expected) in [
+        ("old.com", "https://new.com/"),
+        ("old.com:443", "https://new.com/"),
+        ("old.com:8443", "https://new.com:8443/"),
+    ] {
+        let res = get(&app, host, "/").await;
+        assert_eq!(location(&res), Some(expected), "{}", host);
+    }
+    let app = app_with(&["--preserve-port", "--insecure", "old.com=new.com"]);
+    for (host, expected) in [
+        ("old.com:80", "http://new.com/"),
+        ("old.com:8080", "http://new.com:8080/"),
+    ] {
+        let res = get(&app, host, "/").await;
+        assert_eq!(location(&res), Some(expected), "{}", host);
+    }
+}
+
+#[tokio::test]
+async fn disallowed_methods() {
+    let app = app_with(&["old.com=new.com"]);
+    let res = handle(
+        &app,
+        request_with_method(hyper::Method::POST, "old.com", "/"),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(res.headers()[hyper::header::ALLOW], "GET, HEAD");
+    assert_eq!(location(&res), None);
+    for method in [hyper::Method::GET, hyper::Method::HEAD] {
+        let res = handle(&app, request_with_method(method, "old.com", "/")).await;
+        assert_eq!(res.status(), StatusCode::PERMANENT_REDIRECT);
+    }
+}
+
+#[tokio::test]
+async fn allowed_methods() {
+    let app = app_with(&["--methods", "get,post", "old.com=new.com"]);
+    let res = handle(
+        &app,
+        request_with_method(hyper::Method::POST, "old.com", "/"),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::PERMANENT_REDIRECT);
+    let res = handle(
+        &app,
+        request_with_method(hyper::Method::HEAD, "old.com", "/"),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(res.headers()[hyper::header::ALLOW], "GET, POST");
+}
+
+#[tokio::test]
+async fn trailing_slash() {
+    for (mode, cases) in [
+        (
+            "add",
+            [
+                ("/foo", "/foo/"),
+                ("/foo/", "/foo/"),
+                ("/", "/"),
+                ("/foo?a=b", "/foo/?a=b"),
+            ],
+        ),
+        (
+            "strip",
+            [
+                ("/foo", "/foo"),
+                ("/foo/", "/foo"),
+                ("/", "/"),
+                ("/foo/?a=b", "/foo?a=b"),
+            ],
+        ),
+        (
+            "keep",
+            [
+                ("/foo", "/foo"),
+                ("/foo/", "/foo/"),
+                ("/", "/"),
+                ("/foo/?a=b", "/foo/?a=b"),
+            ],
+        ),
+    ] {
+        let app = app_with(&["--trailing-slash", mode, "old.com=new.com"]);
+        for (uri, expected) in cases {
+            let res = get(&app, "old.com", uri).await;
+            let expected = format!("https://new.com{}", expected);
+            assert_eq!(location(&res), Some(&*expected), "{} {}", mode, uri);
+        }
+    }
+}
+
+#[tokio::test]
+async fn port_qualified_pairs() {
+    let app = app_with(&["example.com=bare.com", "example.com:8080=port.com"]);
+    for (host, expected) in [
+        ("example.com", "https://bare.com/"),
+        ("example.com:8080", "https://port.com/"),
+        ("example.com:9090", "https://bare.com/"),
+    ] {
+        let res = get(&app, host, "/").await;
+        assert_eq!(location(&res), Some(expected), "{}", host);
+    }
+}
+
+#[tokio::test]
+async fn head_has_no_body() {
+    let app = app_with(&["old.com=new.com"]);
+    let res = handle(
+        &app,
+        request_with_method(hyper::Method::HEAD, "old.com", "/"),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::PERMANENT_REDIRECT);
+    assert_eq!(location(&res), Some("https://new.com/"));
+    assert_eq!(
+        res.headers()[hyper::header::CONTENT_LENGTH],
+        "Redirecting".len().to_string().as_str()
+    );
+    assert!(body(res).await.is_empty());
+    let res = get(&app, "old.com", "/").await;
+    assert_eq!(&body(res).await[..], b"Redirecting");
+}
+
+#[tokio::test]
+async fn first_matching_pair_wins() {
+    let app = app_with(&["www.example.com=exact.com", "*.example.com=wildcard.com"]);
+    let res = get(&app, "www.example.com", "/").await;
+    assert_eq!(location(&res), Some("https://exact.com/"));
+    let res = get(&app, "other.example.com", "/").await;
+    assert_eq!(location(&res), Some("https://wildcard.com/"));
+
+    let app = app_with(&["*.example.com=wildcard.com", "www.example.com=exact.com"]);
+    let res = get(&app, "www.example.com", "/").await;
+    assert_eq!(location(&res), Some("https://wildcard.com/"));
+
+    let app = app_with(&["example.com/docs=docs.com", "example.com/=root.com"]);
+    let res = get(&app, "example.com", "/docs/page").await;
+    assert_eq!(location(&res), Some("https://docs.com/docs/page"));
+}
+
+#[tokio::test]
+async fn empty_host_uses_the_fallback() {
+    let app = app_with(&["--fallback", "fallback.com", "old.com=new.com"]);
+    let res = get(&app, "", "/path").await;
+    assert_eq!(res.status(), StatusCode::PERMANENT_REDIRECT);
+    assert_eq!(location(&res), Some("https://fallback.com/path"));
+
+    let app = app_with(&["old.com=new.com"]);
+    let res = get(&app, "", "/path").await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(location(&res), None);
+}
+
+#[tokio::test]
+async fn weighted_split() {
+    let app = app_with(&["old.com=90%new.com,10%other.com"]);
+    let mut new = 0;
+    for _ in 0..2000 {
+        let res = get(&app, "old.com", "/").await;
+        assert_eq!(res.status(), StatusCode::TEMPORARY_REDIRECT);
+        match location(&res) {
+            Some("https://new.com/") => new += 1,
+            Some("https://other.com/") => (),
+            other => panic!("Unexpected location {:?}", other),
+        }
+    }
+    assert!(
+        (1700..1900).contains(&new),
+        "{} of 2000 went to new.com",
+        new
+    );
+}
+
+#[tokio::test]
+async fn strip_fragment() {
+    let app = app_with(&["--strip-fragment", "old.com=new.com"]);
+    for (uri, expected) in [
+        ("/page%23section", "https://new.com/page"),
+        ("/page%23section?a=b", "https://new.com/page"),
+        ("/page?a=b", "https://new.com/page?a=b"),
+    ] {
+        let res = get(&app, "old.com", uri).await;
+        assert_eq!(location(&res), Some(expected), "{}", uri);
+    }
+}
+
+#[tokio::test]
+async fn collapse_slashes() {
+    let app = app_with(&["--collapse-slashes", "old.com=new.com"]);
+    for (uri, expected) in [
+        ("//", "https://new.com/"),
+        ("//foo", "https://new.com/foo"),
+        ("/a//b", "https://new.com/a/b"),
+        ("/a/b?next=//c", "https://new.com/a/b?next=//c"),
+        ("/a/b", "https://new.com/a/b"),
+    ] {
+        let res = get(&app, "old.com", uri).await;
+        assert_eq!(location(&res), Some(expected), "{}", uri);
+    }
+}
+
+#[tokio::test]
+async fn host_header_option() {
+    let app = app_with(&[
+        "--host-header",
+        "x-forwarded-host",
+        "old.com=new.com",
+        "internal.local=internal.com",
+    ]);
+    let mut req = request("internal.local", "/");
+    req.headers_mut().insert(
+        "x-forwarded-host",
+        hyper::header::HeaderValue::from_static("old.com"),
+    );
+    let res = handle(&app, req).await;
+    assert_eq!(location(&res), Some("https://new.com/"));
+    let res = get(&app, "internal.local", "/").await;
+    assert_eq!(location(&res), Some("https://internal.com/"));
+}
+
+#[tokio::test]
+async fn fallback_status() {
+    let app = app_with(&[
+        "--fallback",
+        "fallback.com",
+        "--fallback-status",
+        "302",
+        "old.com=new.com",
+    ]);
+    let res = get(&app, "unknown.com", "/").await;
+    assert_eq!(res.status(), StatusCode::FOUND);
+    assert_eq!(location(&res), Some("https://fallback.com/"));
+    let res = get(&app, "old.com", "/").await;
+    assert_eq!(res.status(), StatusCode::PERMANENT_REDIRECT);
+}
+
+#[tokio::test]
+async fn max_uri_length() {
+    let app = app_with(&["--max-uri-length", "16", "old.com=new.com"]);
+    let res = get(&app, "old.com", "/0123456789abcde").await;
+    assert_eq!(res.status(), StatusCode::PERMANENT_REDIRECT);
+    let res = get(&app, "old.com", "/0123456789abcdef").await;
+    assert_eq!(res.status(), StatusCode::URI_TOO_LONG);
+    assert_eq!(location(&res), None);
+}
+
+#[tokio::test]
+async fn ipv6_destination() {
+    let app = app_with(&["old.com=::1", "other.com=[::1]:8080"]);
+    let res = get(&app, "old.com", "/path").await;
+    assert_eq!(location(&res), Some("https://[::1]/path"));
+    let res = get(&app, "other.com", "/path").await;
+    assert_eq!(location(&res), Some("https://[::1]:8080/path"));
+}
+
+#[tokio::test]
+async fn several_sources() {
+    let app = app_with(&["a.com,b.com,c.com=x.com"]);
+    for host in ["a.com", "b.com", "c.com"] {
+        let res = get(&app, host, "/").await;
+        assert_eq!(location(&res), Some("https://x.com/"), "{}", host);
+    }
+    assert!(App::from_args(["redirector", "a.com,b.com=x.com", "b.com=y.com"]).is_err());
+}
+
+#[tokio::test]
+async fn non_utf8_hosts() {
+    let non_utf8 = |host: &[u8]| {
+        let mut req = request("placeholder", "/");
+        req.headers_mut().insert(
+            HOST,
+            hyper::header::HeaderValue::from_bytes(host).expect("host is a valid header value"),
+        );
+        req
+    };
+    let app = app_with(&["*.example.com=new.com", "xn--bcher-kva.example=books.com"]);
+    let res = handle(&app, non_utf8(b"\xff.example.com")).await;
+    assert_eq!(res.status(), StatusCode::PERMANENT_REDIRECT);
+    assert_eq!(location(&res), Some("https://new.com/"));
+    let res = handle(&app, non_utf8("bücher.example".as_bytes())).await;
+    assert_eq!(location(&res), Some("https://books.com/"));
+    let res = handle(&app, non_utf8(b"\xff.other.com")).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+    let app = app_with(&["--upgrade-only"]);
+    let res = handle(&app, non_utf8(b"\xff.example.com")).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(location(&res), None);
+}
+
+#[tokio::test]
+async fn gone() {
+    let app = app_with(&["old.com=410", "new.com=other.com"]);
+    let res = get(&app, "old.com", "/page").await;
+    assert_eq!(res.status(), StatusCode::GONE);
+    assert_eq!(location(&res), None);
+    assert_eq!(&body(res).await[..], b"Gone");
+}
+
+#[tokio::test]
+async fn client_address_is_logged() {
+    let log_file = std::env::temp_dir().join(format!("redirector-test-{}.log", std::process::id()));
+    let _ = std::fs::remove_file(&log_file);
+    let app = App::from_args([
+        "redirector",
+        "--verbose",
+        "--log-file",
+        log_file.to_str().expect("temporary directory is UTF-8"),
+        "old.com=new.com",
+    ])
+    .expect("configuration is valid");
+    let remote: std::net::SocketAddr = "192.0.2.7:54321".parse().expect("address is valid");
+    let res = app
+        .clone()
+        .handle(request("old.com", "/path"), Some(remote))
+        .await
+        .expect("handling is infallible");
+    assert_eq!(location(&res), Some("https://new.com/path"));
+    let log = std::fs::read_to_string(&log_file).expect("log file is readable");
+    let _ = std::fs::remove_file(&log_file);
+    assert!(
+        log.contains("Received request for http://old.com/path from 192.0.2.7"),
+        "{}",
+        log
+    );
+}
+
+#[tokio::test]
+async fn no_content() {
+    let app = app_with(&["beacon.com=204"]);
+    let res = get(&app, "beacon.com", "/ping").await;
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    assert_eq!(location(&res), None);
+    assert!(body(res).await.is_empty());
+    let res = handle(
+        &app,
+        request_with_method(hyper::Method::HEAD, "beacon.com", "/"),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    assert_eq!(res.headers().get(hyper::header::CONTENT_LENGTH), None);
+}
+
+#[tokio::test]
+async fn trailing_dot() {
+    let app = app_with(&["example.com=new.com", "other.com.=dotted.com"]);
+    for (host, expected) in [
+        ("example.com.", "https://new.com/"),
+        ("example.com.:8080", "https://new.com/"),
+        ("other.com", "https://dotted.com/"),
+    ] {
+        let res = get(&app, host, "/").await;
+        assert_eq!(location(&res), Some(expected), "{}", host);
+    }
+}
+
+#[tokio::test]
+async fn maintenance_file() {
+    let file = std::env::temp_dir().join(format!("redirector-test-{}.down", std::process::id()));
+    let _ = std::fs::remove_file(&file);
+    let args = [
+        "--maintenance",
+        "https://status.example.com",
+        "--maintenance-file",
+        file.to_str().expect("temporary directory is UTF-8"),
+        "old.com=new.com",
+    ];
+    let app = app_with(&args);
+    let res = get(&app, "old.com", "/path").await;
+    assert_eq!(location(&res), Some("https://new.com/path"));
+
+    std::fs::write(&file, "").expect("maintenance file is writable");
+    let app = app_with(&args);
+    let _ = std::fs::remove_file(&file);
+    let res = get(&app, "old.com", "/path").await;
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(location(&res), Some("https://status.example.com"));
+}
+
+#[tokio::test]
+async fn request_id_is_generated() {
+    let app = app_with(&["old.com=new.com"]);
+    let res = get(&app, "old.com", "/path").await;
+    let request_id = res
+        .headers()
+        .get("x-request-id")
+        .expect("response has a request ID");
+    assert!(!request_id.is_empty());
+}
+
+#[tokio::test]
+async fn inbound_request_id() {
+    let inbound = || {
+        Request::builder()
+            .uri("/path")
+            .header(HOST, "old.com")
+            .header("x-request-id", "from-the-proxy")
+            .body(Body::empty())
+            .expect("request is valid")
+    };
+    let app = app_with(&["--trust-request-id", "old.com=new.com"]);
+    let res = handle(&app, inbound()).await;
+    assert_eq!(
+        res.headers().get("x-request-id").map(|id| id.as_bytes()),
+        Some(&b"from-the-proxy"[..])
+    );
+
+    let app = app_with(&["old.com=new.com"]);
+    let res = handle(&app, inbound()).await;
+    let request_id = res
+        .headers()
+        .get("x-request-id")
+        .expect("response has a request ID");
+    assert!(!request_id.is_empty());
+    assert_ne!(request_id, "from-the-proxy");
+}